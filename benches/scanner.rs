@@ -0,0 +1,40 @@
+//! Times lexing a large generated source file. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use rlox::syntax::{scanner::Scanner, token::TokenType};
+
+const ITERATIONS: u32 = 10;
+
+fn scan_all(source: &str) -> usize {
+    let mut scanner = Scanner::new(source);
+    let mut count = 0;
+    loop {
+        match scanner.scan_token() {
+            Ok(token) if *token.token_type() == TokenType::Eof => return count,
+            Ok(_) => count += 1,
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+}
+
+fn main() {
+    let snippet = "var counter = 0; // comment\nwhile (counter < 100) {\n  \
+                   print \"value\" + counter * 2.5;\n  counter = counter + 1;\n}\n";
+    let source = snippet.repeat(50_000);
+
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        count = scan_all(&source);
+        best = best.min(start.elapsed());
+    }
+    println!(
+        "scan_large_source: {} tokens ({} bytes), best of {}: {:?}",
+        count,
+        source.len(),
+        ITERATIONS,
+        best
+    );
+}
@@ -1,51 +1,165 @@
-mod syntax;
-mod vm;
-
 use std::{env, io, process};
 
 use io::{stdout, Write};
 use process::exit;
+use rlox::{
+    syntax::parser::{ParserError, ParserWarning},
+    vm::{
+        bytecode::Chunk,
+        compiler::{compile_into_with_natives, compile_program},
+        disassembler::chunk_to_json,
+        natives::define_builtins,
+        verifier::verify_chunk,
+        vm::Vm,
+    },
+};
 use string_interner::StringInterner;
-use vm::{compiler::compile, vm::Vm};
 
 fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        1 => repl(),
-        2 => run_file(&args[1]),
-        _ => {
-            eprintln!("Usage: rlox [path]\n");
-            exit(64);
+    let mut vm = Vm::default();
+    let mut dump_json = false;
+    let mut coverage = false;
+    let mut call_main = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--call-main" => call_main = true,
+            "--coverage" => coverage = true,
+            "--dump-json" => dump_json = true,
+            "--dedup-output" => vm.set_dedup_output(true),
+            "--json" => vm.set_value_formatter(Box::new(|value, interner| value.to_json(interner))),
+            flag if flag.starts_with("--") => usage(),
+            _ => paths.push(arg),
         }
     }
+    match paths.as_slice() {
+        [] if !dump_json => repl(vm),
+        [path] if dump_json => dump_file(path),
+        [path] => run_file(path, vm, coverage, call_main),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: rlox [--json] [--dedup-output] [--coverage] [--call-main] [path]\n       rlox --dump-json path\n"
+    );
+    exit(64);
 }
 
-fn interpret(source: &str, vm: &mut Vm, interner: &mut StringInterner) {
-    // FIXME: error handling
-    if let Ok(chunk) = compile(source, interner) {
-        let _ = vm.run(chunk, interner);
+fn dump_file(file_path: &str) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let file_contents = std::fs::read_to_string(file_path)?;
+
+    match compile_program(&file_contents) {
+        Ok(program) => {
+            report_warnings(program.warnings());
+            if let Err(err) = verify_chunk(program.chunk(), program.interner()) {
+                eprintln!("{}", err);
+                exit(70)
+            }
+            println!("{}", chunk_to_json(program.chunk(), program.interner()));
+            Ok(())
+        }
+        Err(errors) => {
+            report_compile_errors(&errors);
+            exit(65)
+        }
     }
 }
 
-fn run_file(file_path: &str) -> Result<(), Box<dyn std::error::Error + 'static>> {
+enum InterpretResult {
+    Ok,
+    CompileError,
+    RuntimeError,
+}
+
+fn interpret(source: &str, vm: &mut Vm, interner: &mut StringInterner) -> InterpretResult {
+    let mut chunk = Chunk::default();
+    let natives = vm.native_registry();
+    match compile_into_with_natives(source, &mut chunk, interner, &natives) {
+        Ok(warnings) => report_warnings(&warnings),
+        Err(errors) => {
+            report_compile_errors(&errors);
+            return InterpretResult::CompileError;
+        }
+    }
+    if let Err(err) = verify_chunk(&chunk, interner) {
+        eprintln!("{}", err);
+        return InterpretResult::RuntimeError;
+    }
+    match vm.run(chunk, interner) {
+        Ok(()) => InterpretResult::Ok,
+        Err(err) => {
+            eprintln!("{}", err);
+            InterpretResult::RuntimeError
+        }
+    }
+}
+
+fn report_compile_errors(errors: &[ParserError]) {
+    for err in errors {
+        eprintln!("{}", err);
+    }
+}
+
+fn report_warnings(warnings: &[ParserWarning]) {
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+}
+
+/// With `call_main`, the global function `main` is called once the script's
+/// top-level code has run.
+fn run_file(
+    file_path: &str,
+    mut vm: Vm,
+    coverage: bool,
+    call_main: bool,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
     let file_contents = std::fs::read_to_string(file_path)?;
 
-    let mut vm = Vm::default();
+    vm.enable_coverage(coverage);
     let mut interner = StringInterner::default();
-    interpret(&file_contents, &mut vm, &mut interner);
-    Ok(())
+    define_builtins(&mut vm, &mut interner);
+    let mut result = interpret(&file_contents, &mut vm, &mut interner);
+    if call_main && matches!(result, InterpretResult::Ok) {
+        if let Err(err) = vm.call_global("main", &mut interner) {
+            eprintln!("{}", err);
+            result = InterpretResult::RuntimeError;
+        }
+    }
+    if coverage {
+        let lines: Vec<String> = vm
+            .covered_lines()
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        eprintln!("Covered lines: {}", lines.join(", "));
+    }
+    match result {
+        InterpretResult::Ok => Ok(()),
+        InterpretResult::CompileError => exit(65),
+        InterpretResult::RuntimeError => exit(70),
+    }
 }
 
-fn repl() -> Result<(), Box<dyn std::error::Error + 'static>> {
+fn repl(mut vm: Vm) -> Result<(), Box<dyn std::error::Error + 'static>> {
     let mut interner = StringInterner::default();
-    let mut vm = Vm::default();
+    define_builtins(&mut vm, &mut interner);
 
     let mut input = String::new();
     print_prompt();
 
-    while let Ok(_) = io::stdin().read_line(&mut input) {
-        match input.trim().as_ref() {
+    while io::stdin().read_line(&mut input).is_ok() {
+        match input.trim() {
             "quit" => break,
+            ".vars" => {
+                for (name, value) in vm.dump_globals(&interner) {
+                    println!("{} = {}", name, value);
+                }
+                input.clear();
+                print_prompt();
+            }
             _ => {
                 interpret(&input, &mut vm, &mut interner);
                 input.clear();
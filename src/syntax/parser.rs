@@ -1,18 +1,32 @@
-use std::{convert::TryFrom, fmt::Display};
+use std::{convert::TryFrom, fmt::Display, rc::Rc};
 
-use string_interner::StringInterner;
+use string_interner::{DefaultSymbol, StringInterner};
 
-use crate::vm::bytecode::{ByteCode, Chunk, ChunkConstant, OpCode};
+use crate::vm::{
+    bytecode::{ByteCode, Chunk, ChunkConstant, Offset, OpCode},
+    value::{Function, Value},
+};
 
-use super::{scanner::{Scanner, ScannerError}, token::{LiteralConstant, Token, TokenErrContext, TokenType}};
+use super::{scanner::{Scanner, ScannerError}, token::{Line, LiteralConstant, Token, TokenErrContext, TokenType}};
 
+#[derive(Debug)]
 pub enum ParserError {
     ExpectExpression(TokenErrContext),
     InternalError(TokenErrContext, String),
     InvalidAssignment(TokenErrContext),
+    JumpTooLarge(TokenErrContext),
+    LocalInOwnInitializer(TokenErrContext),
+    LocalRedeclared(TokenErrContext),
+    ReturnOutsideFunction(TokenErrContext),
     ScannerError(ScannerError),
+    ThisOutsideMethod(TokenErrContext),
+    TooDeeplyNested(TokenErrContext),
+    TooManyArguments(TokenErrContext),
     TooManyConstants(TokenErrContext),
+    TooManyLocals(TokenErrContext),
+    TooManyParameters(TokenErrContext),
     UnexpectedToken(TokenErrContext, String),
+    UnmatchedDelimiter(TokenErrContext, char, Line),
 }
 
 impl Display for ParserError {
@@ -21,9 +35,63 @@ impl Display for ParserError {
             ParserError::ExpectExpression(ctx) => write!(f, "{}: Expect expression", ctx),
             ParserError::InternalError(ctx, msg) => write!(f, "{}: {}", ctx, msg),
             ParserError::InvalidAssignment(ctx) => write!(f, "{}: Invalid assignment", ctx),
+            ParserError::JumpTooLarge(ctx) => write!(f, "{}: Too much code to jump over", ctx),
+            ParserError::LocalInOwnInitializer(ctx) => {
+                write!(
+                    f,
+                    "{}: Can't read local variable in its own initializer",
+                    ctx
+                )
+            }
+            ParserError::LocalRedeclared(ctx) => {
+                write!(
+                    f,
+                    "{}: Already a variable with this name in this scope",
+                    ctx
+                )
+            }
+            ParserError::ReturnOutsideFunction(ctx) => {
+                write!(f, "{}: Can't return from top-level code", ctx)
+            }
             ParserError::ScannerError(err) => write!(f, "{}", err),
+            ParserError::ThisOutsideMethod(ctx) => {
+                write!(f, "{}: Can't use 'this' outside of a method", ctx)
+            }
+            ParserError::TooDeeplyNested(ctx) => write!(f, "{}: Code nested too deeply", ctx),
+            ParserError::TooManyArguments(ctx) => {
+                write!(f, "{}: Can't have more than 255 arguments", ctx)
+            }
             ParserError::TooManyConstants(ctx) => write!(f, "{}: Too many constants", ctx),
+            ParserError::TooManyLocals(ctx) => write!(f, "{}: Too many local variables", ctx),
+            ParserError::TooManyParameters(ctx) => {
+                write!(f, "{}: Can't have more than 255 parameters", ctx)
+            }
             ParserError::UnexpectedToken(ctx, msg) => write!(f, "{}: {}", ctx, msg),
+            ParserError::UnmatchedDelimiter(ctx, delimiter, line) => write!(
+                f,
+                "{}: Unmatched '{}' opened at line {}",
+                ctx, delimiter, line
+            ),
+        }
+    }
+}
+
+/// Suspicious but valid code. Warnings don't stop compilation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParserWarning {
+    /// `if (a = b)` is legal but usually a typo for `==`.
+    AssignmentInCondition(Line),
+}
+
+impl Display for ParserWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParserWarning::AssignmentInCondition(line) => write!(
+                f,
+                "[line {}] Warning: Assignment used as a condition; use '==' to compare \
+                 or wrap it in parentheses",
+                line
+            ),
         }
     }
 }
@@ -74,14 +142,56 @@ impl Precedence {
     }
 }
 
+/// A variable declared inside a block, which lives in a stack slot rather
+/// than in the globals table.
+struct Local<'a> {
+    name: &'a str,
+    /// Scope depth of the declaration, or `None` until its initializer has
+    /// been compiled.
+    depth: Option<usize>,
+}
+
+/// Locals are addressed by a single byte slot.
+const MAX_LOCALS: usize = ByteCode::MAX as usize + 1;
+
+/// Argument counts are a single byte operand of `OpCode::Call`.
+const MAX_ARGUMENTS: usize = ByteCode::MAX as usize;
+
 pub struct Parser<'a> {
     scanner: Scanner<'a>,
     chunk: &'a mut Chunk,
     interner: &'a mut StringInterner,
     current: Token<'a>,
     previous: Token<'a>,
+    /// Offset of the most recently emitted `OpCode::Constant`, used for
+    /// constant folding, and whether it added a new slot to the pool.
+    last_constant: Option<(Offset, bool)>,
+    /// Current and maximum nesting of expressions, statements and function
+    /// bodies, which bounds recursion so adversarial input can't overflow the
+    /// stack.
+    depth: usize,
+    max_depth: usize,
+    /// Delimiters that are still waiting to be closed, with the line each
+    /// was opened on, so running out of input can point back at them.
+    open_delimiters: Vec<(char, Line)>,
+    /// Locals in scope, in stack slot order, and the current block nesting
+    /// (0 at the top level, where variables are globals).
+    locals: Vec<Local<'a>>,
+    scope_depth: usize,
+    /// Number of function bodies being compiled, so `return` can be
+    /// rejected at the top level.
+    function_depth: usize,
+    /// Expression depth and line of the most recent assignment, so a
+    /// condition can tell whether it is a bare assignment.
+    last_assignment: Option<(usize, Line)>,
+    warnings: Vec<ParserWarning>,
+    /// Natives that calls can be fused into `OpCode::CallNative` for,
+    /// indexed by id.
+    natives: &'a [Option<DefaultSymbol>],
 }
 
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
 type ParseFn<'a> = fn(&mut Parser<'a>, bool) -> Result<(), ParserError>;
 
 struct ParseRule<'a> {
@@ -116,9 +226,37 @@ impl<'a> Parser<'a> {
             interner,
             current: Token::new(TokenType::Eof, "", LiteralConstant::None, 0),
             previous: Token::new(TokenType::Eof, "", LiteralConstant::None, 0),
+            last_constant: None,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            open_delimiters: Vec::new(),
+            locals: Vec::new(),
+            scope_depth: 0,
+            function_depth: 0,
+            last_assignment: None,
+            warnings: Vec::new(),
+            natives: &[],
         }
     }
 
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Sets the registry of natives the code will run with, from
+    /// `Vm::native_registry`.
+    pub fn set_natives(&mut self, natives: &'a [Option<DefaultSymbol>]) {
+        self.natives = natives;
+    }
+
+    pub fn warnings(&self) -> &[ParserWarning] {
+        &self.warnings
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<ParserWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
     /// Returns true if we're done or haven't yet started via `advance()`.
     pub fn is_done(&self) -> bool {
         self.scanner.at_end()
@@ -126,6 +264,7 @@ impl<'a> Parser<'a> {
 
     pub fn end(&mut self) {
         self.emit_opcode(OpCode::Return);
+        self.chunk.finalize();
     }
 
     fn binary(&mut self, _can_assign: bool) -> Result<(), ParserError> {
@@ -161,10 +300,27 @@ impl<'a> Parser<'a> {
             }
         };
 
+        let left_constant = self.trailing_constant();
+        let left_end = self.chunk.len();
+
         // Compile the right operand.
         let rule = Parser::get_rule(op_type);
         self.parse_precedence(rule.precedence.one_higher())?;
 
+        if let (Some((left_offset, left, left_new)), Some((right_offset, right, right_new))) =
+            (left_constant, self.trailing_constant())
+        {
+            if right_offset == left_end {
+                if let Some(folded) = self.fold_binary(opcode, &left, &right) {
+                    // The operands' slots are only used by the loads being
+                    // replaced, and the right one was added last.
+                    self.discard_constant(right_offset, right_new);
+                    self.discard_constant(left_offset, left_new);
+                    return self.emit_value(folded);
+                }
+            }
+        }
+
         self.emit_opcode(opcode);
         if add_not {
             self.emit_opcode(OpCode::Not);
@@ -172,8 +328,87 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Returns the offset and value of the last emitted instruction if it is
+    /// an `OpCode::Constant`, `OpCode::ConstantLong` or a literal such as
+    /// `OpCode::True`, and whether it added the last slot of the constant pool.
+    fn trailing_constant(&self) -> Option<(Offset, Value, bool)> {
+        let (offset, new) = self.last_constant?;
+        let opcode = OpCode::try_from(*self.chunk.get_bytecode(offset)?).ok()?;
+        if offset + 1 + opcode.operand_len() != self.chunk.len() {
+            return None;
+        }
+        let value = match opcode {
+            OpCode::Nil => Value::Nil,
+            OpCode::True => Value::Bool(true),
+            OpCode::False => Value::Bool(false),
+            _ => {
+                let constant_idx = self.chunk.constant_index(offset)?;
+                self.chunk.get_constant(constant_idx)?.clone()
+            }
+        };
+        Some((offset, value, new))
+    }
+
+    /// Removes the trailing constant at `offset`, and its slot in the pool if
+    /// it was new.
+    fn discard_constant(&mut self, offset: Offset, new: bool) {
+        self.chunk.truncate(offset);
+        if new {
+            self.chunk.pop_constant();
+        }
+        self.last_constant = None;
+    }
+
+    /// Evaluates `left <opcode> right` at compile time. Returns `None` if the
+    /// operation should be left to runtime, which includes results that
+    /// wouldn't be finite such as overflow or division by zero.
+    fn fold_binary(&mut self, opcode: OpCode, left: &Value, right: &Value) -> Option<Value> {
+        use Value::*;
+        let result = match (opcode, left, right) {
+            (OpCode::Add, Number(a), Number(b)) => a + b,
+            (OpCode::Subtract, Number(a), Number(b)) => a - b,
+            (OpCode::Multiply, Number(a), Number(b)) => a * b,
+            (OpCode::Divide, Number(a), Number(b)) if *b != 0.0 => a / b,
+            (OpCode::Add, InternedString(a), InternedString(b)) => {
+                let folded = self.interner.resolve(*a)?.to_owned() + self.interner.resolve(*b)?;
+                return Some(InternedString(self.interner.get_or_intern(folded)));
+            }
+            _ => return None,
+        };
+        if result.is_finite() {
+            Some(Number(result))
+        } else {
+            None
+        }
+    }
+
+    /// If the left operand is falsey it is the result, so skip the right.
+    fn and_(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.parse_precedence(Precedence::And)?;
+        self.patch_jump(end_jump)
+    }
+
+    /// If the left operand is truthy it is the result, so skip the right.
+    fn or_(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump)?;
+        self.emit_opcode(OpCode::Pop);
+        self.parse_precedence(Precedence::Or)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn dot(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.")?;
+        let name = self.parse_variable();
+        self.emit_constant(name, OpCode::GetProperty)
+    }
+
     fn literal(&mut self, _can_assign: bool) -> Result<(), ParserError> {
         let prev = &self.previous;
+        let offset = self.chunk.len();
         match prev.token_type() {
             TokenType::False => self.emit_opcode(OpCode::False),
             TokenType::Nil => self.emit_opcode(OpCode::Nil),
@@ -184,6 +419,7 @@ impl<'a> Parser<'a> {
                 return Err(ParserError::InternalError(err_ctx, msg));
             }
         }
+        self.last_constant = Some((offset, false));
         Ok(())
     }
 
@@ -191,21 +427,216 @@ impl<'a> Parser<'a> {
         self.parse_precedence(Precedence::Assignment)
     }
 
-    fn var_declaration(&mut self) -> Result<(), ParserError> {
-        self.consume(TokenType::Identifier, "Expect variable name.")?;
-        let maybe_global = self.parse_variable();
+    fn fun_declaration(&mut self) -> Result<(), ParserError> {
+        self.consume(TokenType::Identifier, "Expect function name.")?;
+        let name = self.previous.lexeme();
+        let maybe_global = if self.scope_depth > 0 {
+            self.declare_local()?;
+            None
+        } else {
+            Some(self.parse_variable())
+        };
 
-        if self.match_token(TokenType::Equal)? {
-            self.expression()?;
+        self.function(name, "")?;
+        match maybe_global {
+            Some(global) => self.emit_constant(global, OpCode::DefineGlobal),
+            None => {
+                self.mark_initialized();
+                Ok(())
+            }
+        }
+    }
+
+    /// Compiles a function used as a value, such as
+    /// `(fun (x) { return x * 2; })(21)`. A name is optional and only in scope
+    /// inside the body, so the function can call itself.
+    fn fun_expression(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        if self.match_token(TokenType::Identifier)? {
+            let name = self.previous.lexeme();
+            self.function(name, name)
         } else {
-            self.emit_opcode(OpCode::Nil);
+            self.function("", "")
+        }
+    }
+
+    /// Compiles a function's parameters and body into a chunk of its own and
+    /// emits the resulting function as a constant. Anonymous functions are
+    /// named `""`. Inside the body, `self_name` refers to the function itself.
+    fn function(&mut self, name: &'a str, self_name: &'a str) -> Result<(), ParserError> {
+        self.nest()?;
+        let result = self.nested_function(name, self_name);
+        self.depth -= 1;
+        result
+    }
+
+    fn nested_function(&mut self, name: &'a str, self_name: &'a str) -> Result<(), ParserError> {
+        // The body gets a fresh chunk and locals; the enclosing code's are
+        // restored afterwards, even if the body has errors.
+        let enclosing_chunk = std::mem::take(&mut *self.chunk);
+        let enclosing_locals = std::mem::take(&mut self.locals);
+        let enclosing_scope_depth = std::mem::replace(&mut self.scope_depth, 0);
+        let enclosing_last_constant = self.last_constant.take();
+
+        self.function_depth += 1;
+        let result = self.function_body(self_name);
+        self.function_depth -= 1;
+
+        let chunk = std::mem::replace(&mut *self.chunk, enclosing_chunk);
+        self.locals = enclosing_locals;
+        self.scope_depth = enclosing_scope_depth;
+        self.last_constant = enclosing_last_constant;
+
+        let function = Function {
+            arity: result?,
+            chunk,
+            name: Some(self.interner.get_or_intern(name)),
+        };
+        self.emit_value(Value::Function(Rc::new(function)))
+    }
+
+    /// Returns the function's arity.
+    fn function_body(&mut self, self_name: &'a str) -> Result<usize, ParserError> {
+        // Slot 0 holds the function being called. A method's would hold its
+        // receiver, as the local `this`.
+        self.locals.push(Local {
+            name: self_name,
+            depth: Some(0),
+        });
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.")?;
+        let mut arity = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if arity == MAX_ARGUMENTS {
+                    let err_ctx = self.current.to_err_context();
+                    return Err(ParserError::TooManyParameters(err_ctx));
+                }
+                arity += 1;
+                self.consume(TokenType::Identifier, "Expect parameter name.")?;
+                self.declare_local()?;
+                self.mark_initialized();
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.")?;
+        self.open_delimiters.push(('{', self.previous.line()));
+        self.block()?;
+        self.open_delimiters.pop();
+
+        // Reaching the end of the body returns nil.
+        self.emit_opcode(OpCode::Nil);
+        self.emit_opcode(OpCode::Return);
+        self.chunk.finalize();
+        Ok(arity)
+    }
+
+    fn var_declaration(&mut self) -> Result<(), ParserError> {
+        // Each binding is defined before the next initializer runs, so
+        // `var a = 1, b = a;` sees `a`.
+        loop {
+            self.consume(TokenType::Identifier, "Expect variable name.")?;
+            let maybe_global = if self.scope_depth > 0 {
+                self.declare_local()?;
+                None
+            } else {
+                Some(self.parse_variable())
+            };
+
+            if self.match_token(TokenType::Equal)? {
+                self.expression()?;
+            } else {
+                self.emit_opcode(OpCode::Nil);
+            }
+            match maybe_global {
+                Some(name) => self.emit_constant(name, OpCode::DefineGlobal)?,
+                // The initializer's value stays on the stack as the local's slot.
+                None => self.mark_initialized(),
+            }
+
+            if !self.match_token(TokenType::Comma)? {
+                break;
+            }
         }
         self.consume(
             TokenType::Semicolon,
             "Expect ';' after variable declaration.",
-        )?;
+        )
+    }
 
-        self.emit_constant(maybe_global, OpCode::DefineGlobal)
+    /// Adds the identifier just consumed as a local in the current scope.
+    fn declare_local(&mut self) -> Result<(), ParserError> {
+        let name = self.previous.lexeme();
+        let scope_depth = self.scope_depth;
+        let redeclared = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+        if redeclared {
+            return Err(ParserError::LocalRedeclared(self.previous.to_err_context()));
+        }
+        if self.locals.len() == MAX_LOCALS {
+            return Err(ParserError::TooManyLocals(self.previous.to_err_context()));
+        }
+        self.locals.push(Local { name, depth: None });
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+        }
+    }
+
+    /// Returns the stack slot of the innermost local called `name`, if any.
+    fn resolve_local(&self, name: &str) -> Result<Option<ByteCode>, ParserError> {
+        match self.locals.iter().rposition(|local| local.name == name) {
+            Some(slot) if self.locals[slot].depth.is_none() => Err(
+                ParserError::LocalInOwnInitializer(self.previous.to_err_context()),
+            ),
+            // `declare_local` caps the number of locals, so the slot fits.
+            Some(slot) => Ok(Some(slot as ByteCode)),
+            None => Ok(None),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+        let scope_depth = self.scope_depth;
+        // An uninitialized local is only left behind by a failed declaration
+        // in this scope, so it goes too.
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|depth| depth > scope_depth))
+        {
+            self.emit_opcode(OpCode::Pop);
+            self.locals.pop();
+        }
+    }
+
+    fn block(&mut self) -> Result<(), ParserError> {
+        // Keep compiling after an error so the scope's locals are still
+        // tracked and popped; the first error is reported.
+        let mut result = Ok(());
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if let Err(err) = self.declaration() {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result?;
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")
     }
 
     fn expression_statement(&mut self) -> Result<(), ParserError> {
@@ -222,6 +653,206 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Desugars `for (init; condition; increment) body` into jumps. The
+    /// caller wraps it in a scope so an initializer variable is popped.
+    fn for_statement(&mut self) -> Result<(), ParserError> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        if self.match_token(TokenType::Semicolon)? {
+            // No initializer.
+        } else if self.match_token(TokenType::Var)? {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement()?;
+        }
+
+        let mut loop_start = self.chunk.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon)? {
+            self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+            let condition_line = self.previous.line();
+            exit_jump = Some((self.emit_jump(OpCode::JumpIfFalse), condition_line));
+            self.emit_opcode(OpCode::Pop);
+        }
+
+        if !self.match_token(TokenType::RightParen)? {
+            // The increment is compiled before the body but runs after it:
+            // jump over it now, and loop back to it from the body.
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk.len();
+            self.expression()?;
+            self.emit_opcode(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        if let Some((exit_jump, condition_line)) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit_condition_pop(condition_line);
+        }
+        Ok(())
+    }
+
+    /// A condition that is a compile-time constant only compiles the branch
+    /// it takes: the other branch is still checked for errors, but its code
+    /// is dropped along with any side effects it would have had.
+    fn if_statement(&mut self) -> Result<(), ParserError> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        self.condition()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let condition_line = self.previous.line();
+
+        if let Some((offset, condition, new)) = self.trailing_constant() {
+            self.discard_constant(offset, new);
+            let taken = !condition.is_falsey();
+            if taken {
+                self.statement()?;
+            } else {
+                self.dead_statement()?;
+            }
+            if self.match_token(TokenType::Else)? {
+                if taken {
+                    self.dead_statement()?;
+                } else {
+                    self.statement()?;
+                }
+            }
+            return Ok(());
+        }
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.statement()?;
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump)?;
+        self.emit_condition_pop(condition_line);
+        if self.match_token(TokenType::Else)? {
+            self.statement()?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    /// Compiles an `if`/`while` condition, warning if it is an unparenthesized
+    /// assignment. Only an assignment parsed directly at the condition's own
+    /// depth counts: one inside a grouping is nested deeper.
+    fn condition(&mut self) -> Result<(), ParserError> {
+        self.last_assignment = None;
+        self.expression()?;
+        if let Some((depth, line)) = self.last_assignment {
+            if depth == self.depth + 1 {
+                let warning = ParserWarning::AssignmentInCondition(line);
+                self.warnings.push(warning);
+            }
+        }
+        Ok(())
+    }
+
+    /// Desugars `repeat (count) body` into a loop over a hidden local. The
+    /// local counts up from `-count` to zero, so negating the count rejects
+    /// one that isn't a number at runtime. A count of zero or less runs the
+    /// body zero times, and a fractional count is rounded up. The caller
+    /// wraps it in a scope so the hidden local is popped.
+    fn repeat_statement(&mut self) -> Result<(), ParserError> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'repeat'.")?;
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after repeat count.")?;
+        let count_line = self.previous.line();
+        self.emit_opcode(OpCode::Negate);
+        if self.locals.len() == MAX_LOCALS {
+            return Err(ParserError::TooManyLocals(self.previous.to_err_context()));
+        }
+        let slot = self.locals.len() as ByteCode;
+        self.locals.push(Local {
+            name: "",
+            depth: Some(self.scope_depth),
+        });
+
+        let loop_start = self.chunk.len();
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_bytecode(slot);
+        self.emit_literal(ChunkConstant::Number(0.0))?;
+        self.emit_opcode(OpCode::Less);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.statement()?;
+
+        self.emit_opcode(OpCode::GetLocal);
+        self.emit_bytecode(slot);
+        self.emit_literal(ChunkConstant::Number(1.0))?;
+        self.emit_opcode(OpCode::Add);
+        self.emit_opcode(OpCode::SetLocal);
+        self.emit_bytecode(slot);
+        self.emit_opcode(OpCode::Pop);
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_condition_pop(count_line);
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<(), ParserError> {
+        if self.function_depth == 0 {
+            return Err(ParserError::ReturnOutsideFunction(
+                self.previous.to_err_context(),
+            ));
+        }
+        if self.match_token(TokenType::Semicolon)? {
+            self.emit_opcode(OpCode::Nil);
+        } else {
+            self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+        }
+        self.emit_opcode(OpCode::Return);
+        Ok(())
+    }
+
+    /// As with `if`, a constant condition either drops the loop or loops
+    /// without testing it.
+    fn while_statement(&mut self) -> Result<(), ParserError> {
+        let loop_start = self.chunk.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        self.condition()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let condition_line = self.previous.line();
+
+        if let Some((offset, condition, new)) = self.trailing_constant() {
+            self.discard_constant(offset, new);
+            if condition.is_falsey() {
+                return self.dead_statement();
+            }
+            self.statement()?;
+            return self.emit_loop(loop_start);
+        }
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_opcode(OpCode::Pop);
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_condition_pop(condition_line);
+        Ok(())
+    }
+
+    /// Compiles a statement that can never run, for its errors, and then
+    /// drops its code and the constants it added.
+    fn dead_statement(&mut self) -> Result<(), ParserError> {
+        let code_len = self.chunk.len();
+        let pool_len = self.chunk.constants().len();
+        let result = self.statement();
+        self.chunk.truncate(code_len);
+        self.chunk.truncate_constants(pool_len);
+        self.last_constant = None;
+        result
+    }
+
     fn synchronize(&mut self) {
         use TokenType::*;
         while *self.current.token_type() != Eof {
@@ -231,7 +862,7 @@ impl<'a> Parser<'a> {
             }
 
             match *self.current.token_type() {
-                Class | Fun | Var | For | If | While | Print | Return => {
+                Class | Fun | Var | For | If | While | Print | Repeat | Return => {
                     return;
                 }
                 _ => {}
@@ -244,12 +875,27 @@ impl<'a> Parser<'a> {
     }
 
     pub fn declaration(&mut self) -> Result<(), ParserError> {
-        let result = if self.match_token(TokenType::Var)? {
+        let open_delimiters = self.open_delimiters.len();
+        let result = if self.match_token(TokenType::Fun)? {
+            self.fun_declaration()
+        } else if self.match_token(TokenType::Var)? {
             self.var_declaration()
         } else {
             self.statement()
         };
         if let Err(err) = result {
+            // Recovering from nesting that is too deep skips to the end, but
+            // the input didn't necessarily end early.
+            let err = match self.open_delimiters.last() {
+                _ if matches!(err, ParserError::TooDeeplyNested(_)) => err,
+                Some(&(delimiter, line)) if self.check(TokenType::Eof) => {
+                    ParserError::UnmatchedDelimiter(self.current.to_err_context(), delimiter, line)
+                }
+                _ => err,
+            };
+            // Forget delimiters opened by this declaration, but not those of
+            // an enclosing block.
+            self.open_delimiters.truncate(open_delimiters);
             self.synchronize();
             Err(err)
         } else {
@@ -258,8 +904,39 @@ impl<'a> Parser<'a> {
     }
 
     fn statement(&mut self) -> Result<(), ParserError> {
+        self.nest()?;
+        let result = self.nested_statement();
+        self.depth -= 1;
+        result
+    }
+
+    fn nested_statement(&mut self) -> Result<(), ParserError> {
         if self.match_token(TokenType::Print)? {
             self.print_statement()
+        } else if self.match_token(TokenType::For)? {
+            self.begin_scope();
+            let result = self.for_statement();
+            self.end_scope();
+            result
+        } else if self.match_token(TokenType::If)? {
+            self.if_statement()
+        } else if self.match_token(TokenType::Repeat)? {
+            self.begin_scope();
+            let result = self.repeat_statement();
+            self.end_scope();
+            result
+        } else if self.match_token(TokenType::Return)? {
+            self.return_statement()
+        } else if self.match_token(TokenType::While)? {
+            self.while_statement()
+        } else if self.match_token(TokenType::LeftBrace)? {
+            self.open_delimiters.push(('{', self.previous.line()));
+            self.begin_scope();
+            let result = self.block();
+            self.end_scope();
+            result?;
+            self.open_delimiters.pop();
+            Ok(())
         } else {
             self.expression_statement()
         }
@@ -267,7 +944,10 @@ impl<'a> Parser<'a> {
 
     fn get_rule(token: &TokenType) -> ParseRule<'a> {
         match token {
-            TokenType::LeftParen => ParseRule::new(Some(Parser::grouping), None, Precedence::None),
+            TokenType::LeftParen => {
+                ParseRule::new(Some(Parser::grouping), Some(Parser::call), Precedence::Call)
+            }
+            TokenType::Dot => ParseRule::new(None, Some(Parser::dot), Precedence::Call),
             TokenType::False | TokenType::Nil | TokenType::True => {
                 ParseRule::new(Some(Parser::literal), None, Precedence::None)
             }
@@ -291,11 +971,33 @@ impl<'a> Parser<'a> {
             TokenType::Identifier => ParseRule::new(Some(Parser::variable), None, Precedence::None),
             TokenType::String => ParseRule::new(Some(Parser::string), None, Precedence::None),
             TokenType::Number => ParseRule::new(Some(Parser::number), None, Precedence::None),
+            TokenType::Char => ParseRule::new(Some(Parser::char), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(Parser::this_), None, Precedence::None),
+            TokenType::Fun => ParseRule::new(Some(Parser::fun_expression), None, Precedence::None),
+            TokenType::And => ParseRule::new(None, Some(Parser::and_), Precedence::And),
+            TokenType::Or => ParseRule::new(None, Some(Parser::or_), Precedence::Or),
             _ => ParseRule::new(None, None, Precedence::None),
         }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), ParserError> {
+        self.nest()?;
+        let result = self.parse_nested_precedence(precedence);
+        self.depth -= 1;
+        result
+    }
+
+    /// Enters one more level of nesting. The caller leaves it again by
+    /// decrementing `depth`.
+    fn nest(&mut self) -> Result<(), ParserError> {
+        if self.depth >= self.max_depth {
+            return Err(ParserError::TooDeeplyNested(self.current.to_err_context()));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn parse_nested_precedence(&mut self, precedence: Precedence) -> Result<(), ParserError> {
         self.advance()?;
         if let Some(prefix_fn) = Parser::get_rule(self.previous.token_type()).prefix {
             let can_assign = precedence <= Precedence::Assignment;
@@ -323,19 +1025,70 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// There are no methods yet, so `this` never has a receiver to refer to.
+    fn this_(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        Err(ParserError::ThisOutsideMethod(
+            self.previous.to_err_context(),
+        ))
+    }
+
     fn grouping(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        self.open_delimiters.push(('(', self.previous.line()));
         self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+        self.open_delimiters.pop();
+        Ok(())
+    }
+
+    fn call(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        let arg_count = self.argument_list()?;
+        self.emit_opcode(OpCode::Call);
+        self.emit_bytecode(arg_count);
         Ok(())
     }
 
+    fn argument_list(&mut self) -> Result<ByteCode, ParserError> {
+        self.open_delimiters.push(('(', self.previous.line()));
+        let mut arg_count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression()?;
+                if arg_count == MAX_ARGUMENTS {
+                    let err_ctx = self.previous.to_err_context();
+                    return Err(ParserError::TooManyArguments(err_ctx));
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.")?;
+        self.open_delimiters.pop();
+        Ok(arg_count as ByteCode)
+    }
+
     fn unary(&mut self, _can_assign: bool) -> Result<(), ParserError> {
         let prev = &self.previous;
         match prev.token_type() {
             // FIXME: We need to pass the line number here.
             TokenType::Bang => {
                 self.parse_precedence(Precedence::Unary)?; // Compile the operand.
-                self.emit_opcode(OpCode::Not);
+                match self.trailing_constant() {
+                    // Truthiness is fixed (only nil and false are falsey), so
+                    // the runtime would compute the same result.
+                    Some((offset, operand, new)) => {
+                        self.discard_constant(offset, new);
+                        let folded = if operand.is_falsey() {
+                            OpCode::True
+                        } else {
+                            OpCode::False
+                        };
+                        self.emit_opcode(folded);
+                        self.last_constant = Some((offset, false));
+                    }
+                    None => self.emit_opcode(OpCode::Not),
+                }
             }
             TokenType::Minus => {
                 self.parse_precedence(Precedence::Unary)?; // Compile the operand.
@@ -350,62 +1103,105 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
-    fn parse_variable(&mut self) -> Option<ByteCode> {
+    fn parse_variable(&mut self) -> Option<usize> {
         let name = self.previous.lexeme();
         self.chunk
-            .add_constant(&mut self.interner, ChunkConstant::String(name))
+            .add_constant(self.interner, ChunkConstant::String(name))
     }
 
     fn string(&mut self, _can_assign: bool) -> Result<(), ParserError> {
         if *self.previous.token_type() == TokenType::String {
             if let LiteralConstant::String(str) = self.previous.literal() {
-                let maybe_global = self
+                // Escaped strings borrow the token, so this can't go
+                // through `emit_literal`.
+                let pool_len = self.chunk.constants().len();
+                let idx = self
                     .chunk
-                    .add_constant(&mut self.interner, ChunkConstant::String(str));
-                return self.emit_constant(maybe_global, OpCode::Constant);
+                    .add_constant(self.interner, ChunkConstant::String(str));
+                return self.emit_load(idx, pool_len);
             }
         }
-        return Err(ParserError::InternalError(
+        Err(ParserError::InternalError(
             self.previous.to_err_context(),
             "invalid string literal".to_string(),
-        ));
+        ))
     }
 
     fn named_variable(&mut self, can_assign: bool) -> Result<(), ParserError> {
         let name = self.previous.lexeme();
-        let maybe_global = self
-            .chunk
-            .add_constant(&mut self.interner, ChunkConstant::String(name));
-
-        if maybe_global == None {
-            return Err(self.err_constants());
+        let local = self.resolve_local(name)?;
+        if local.is_none() && self.check(TokenType::LeftParen) {
+            if let Some(id) = self.native_id(name) {
+                return self.native_call(id);
+            }
         }
+        let (arg, get_op, set_op) = match local {
+            Some(slot) => (slot as usize, OpCode::GetLocal, OpCode::SetLocal),
+            None => {
+                let maybe_global = self
+                    .chunk
+                    .add_constant(self.interner, ChunkConstant::String(name));
+                let global = maybe_global.ok_or_else(|| self.err_constants())?;
+                (global, OpCode::GetGlobal, OpCode::SetGlobal)
+            }
+        };
 
         if can_assign && self.match_token(TokenType::Equal)? {
+            let line = self.previous.line();
             self.expression()?;
-            self.emit_constant(maybe_global, OpCode::SetGlobal)
+            self.emit_indexed(set_op, arg)?;
+            self.last_assignment = Some((self.depth, line));
         } else {
-            self.emit_constant(maybe_global, OpCode::GetGlobal)
+            self.emit_indexed(get_op, arg)?;
         }
+        Ok(())
+    }
+
+    /// Returns the id of the native `name` refers to, if it's in the registry.
+    fn native_id(&self, name: &str) -> Option<ByteCode> {
+        let symbol = self.interner.get(name)?;
+        let id = self
+            .natives
+            .iter()
+            .position(|native| *native == Some(symbol))?;
+        ByteCode::try_from(id).ok()
+    }
+
+    /// Compiles a call to a native as `OpCode::CallNative`, which skips
+    /// looking up the global and pushing the callee.
+    fn native_call(&mut self, id: ByteCode) -> Result<(), ParserError> {
+        self.advance()?;
+        let arg_count = self.argument_list()?;
+        self.emit_opcode(OpCode::CallNative);
+        self.emit_bytecode(id);
+        self.emit_bytecode(arg_count);
+        Ok(())
     }
 
     fn variable(&mut self, can_assign: bool) -> Result<(), ParserError> {
         self.named_variable(can_assign)
     }
 
+    fn char(&mut self, _can_assign: bool) -> Result<(), ParserError> {
+        if let LiteralConstant::Char(char) = *self.previous.literal() {
+            return self.emit_literal(ChunkConstant::Char(char));
+        }
+        Err(ParserError::InternalError(
+            self.previous.to_err_context(),
+            "invalid char literal".to_string(),
+        ))
+    }
+
     fn number(&mut self, _can_assign: bool) -> Result<(), ParserError> {
         if *self.previous.token_type() == TokenType::Number {
-            if let LiteralConstant::Number(num) = self.previous.literal() {
-                let res = self
-                    .chunk
-                    .add_constant(&mut self.interner, ChunkConstant::Number(num));
-                return self.emit_constant(res, OpCode::Constant);
+            if let LiteralConstant::Number(num) = *self.previous.literal() {
+                return self.emit_literal(ChunkConstant::Number(num));
             }
         }
-        return Err(ParserError::InternalError(
+        Err(ParserError::InternalError(
             self.previous.to_err_context(),
             "invalid number literal".to_string(),
-        ));
+        ))
     }
 
     pub fn advance(&mut self) -> Result<(), ParserError> {
@@ -455,22 +1251,456 @@ impl<'a> Parser<'a> {
         self.emit_bytecode(opcode as u8);
     }
 
+    /// Pops a condition on the path where its jump was taken. The pop is
+    /// attributed to the condition's line rather than to the end of the
+    /// skipped body, so line-based tools don't count the body as run.
+    fn emit_condition_pop(&mut self, line: Line) {
+        self.chunk.write(OpCode::Pop as ByteCode, line);
+    }
+
+    /// Emits `opcode` with a placeholder 16-bit offset and returns the
+    /// offset of the operand, to be filled in by `patch_jump`.
+    fn emit_jump(&mut self, opcode: OpCode) -> Offset {
+        self.emit_opcode(opcode);
+        self.emit_bytecode(0xff);
+        self.emit_bytecode(0xff);
+        self.chunk.len() - 2
+    }
+
+    /// Points the jump whose operand is at `offset` at the next instruction.
+    fn patch_jump(&mut self, offset: Offset) -> Result<(), ParserError> {
+        let jump = self.chunk.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            return Err(ParserError::JumpTooLarge(self.previous.to_err_context()));
+        }
+        self.chunk.patch(offset, (jump >> 8) as ByteCode);
+        self.chunk.patch(offset + 1, jump as ByteCode);
+        // Code before a jump target may be skipped, so it can't be folded
+        // with what follows.
+        self.last_constant = None;
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: Offset) -> Result<(), ParserError> {
+        self.emit_opcode(OpCode::Loop);
+        let jump = self.chunk.len() - loop_start + 2;
+        if jump > u16::MAX as usize {
+            return Err(ParserError::JumpTooLarge(self.previous.to_err_context()));
+        }
+        self.emit_bytecode((jump >> 8) as ByteCode);
+        self.emit_bytecode(jump as ByteCode);
+        Ok(())
+    }
+
+    /// Emits `opcode` with a constant pool index operand.
     fn emit_constant(
         &mut self,
-        maybe_global: Option<ByteCode>,
+        maybe_global: Option<usize>,
         opcode: OpCode,
     ) -> Result<(), ParserError> {
-        match maybe_global {
-            Some(idx) => {
+        let idx = maybe_global.ok_or_else(|| self.err_constants())?;
+        self.emit_indexed(opcode, idx)
+    }
+
+    /// Emits an `OpCode::Constant` that loads a literal.
+    fn emit_literal(&mut self, constant: ChunkConstant) -> Result<(), ParserError> {
+        let pool_len = self.chunk.constants().len();
+        let idx = self.chunk.add_constant(self.interner, constant);
+        self.emit_load(idx, pool_len)
+    }
+
+    /// Emits an `OpCode::Constant` that loads an already-built value.
+    fn emit_value(&mut self, value: Value) -> Result<(), ParserError> {
+        let pool_len = self.chunk.constants().len();
+        let idx = self.chunk.add_value(value);
+        self.emit_load(idx, pool_len)
+    }
+
+    /// Emits an `OpCode::Constant` for the pool slot `idx` and remembers it
+    /// for constant folding. The pool had `pool_len` slots before the
+    /// constant was added, so a higher index is a new slot.
+    fn emit_load(&mut self, idx: Option<usize>, pool_len: usize) -> Result<(), ParserError> {
+        let idx = idx.ok_or_else(|| self.err_constants())?;
+        self.last_constant = Some((self.chunk.len(), idx >= pool_len));
+        self.emit_indexed(OpCode::Constant, idx)
+    }
+
+    /// Emits `opcode` with a one-byte operand, switching to its long form
+    /// (such as `OpCode::ConstantLong`) for indices beyond the first 256.
+    fn emit_indexed(&mut self, opcode: OpCode, idx: usize) -> Result<(), ParserError> {
+        match (ByteCode::try_from(idx), opcode.long_form()) {
+            (Ok(idx), _) => {
                 self.emit_opcode(opcode);
                 self.emit_bytecode(idx);
-                Ok(())
             }
-            None => Err(self.err_constants()),
+            (Err(_), Some(long_form)) => {
+                self.emit_opcode(long_form);
+                self.emit_bytecode((idx >> 16) as ByteCode);
+                self.emit_bytecode((idx >> 8) as ByteCode);
+                self.emit_bytecode(idx as ByteCode);
+            }
+            (Err(_), None) => return Err(self.err_constants()),
         }
+        Ok(())
     }
 
     fn err_constants(&self) -> ParserError {
         ParserError::TooManyConstants(self.previous.to_err_context())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use string_interner::StringInterner;
+
+    use super::*;
+    use crate::vm::compiler::compile;
+
+    #[test]
+    fn folds_adjacent_string_literals() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("print \"foo\" + \"bar\";", &mut interner).unwrap();
+
+        assert_eq!(chunk.len(), 4);
+        assert_eq!(chunk[0], OpCode::Constant as u8);
+        assert_eq!(chunk[2], OpCode::Print as u8);
+        assert_eq!(chunk[3], OpCode::Return as u8);
+        // The operands' constants are dropped along with their loads.
+        assert_eq!(chunk.constants().len(), 1);
+        match chunk.get_constant(chunk[1] as usize) {
+            Some(Value::InternedString(symbol)) => {
+                assert_eq!(interner.resolve(*symbol), Some("foobar"))
+            }
+            other => panic!("Unexpected constant: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn does_not_fold_non_literal_operands() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("print a + \"bar\";", &mut interner).unwrap();
+
+        assert_eq!(chunk[4], OpCode::Add as u8);
+    }
+
+    #[test]
+    fn rejects_deeply_nested_expressions() {
+        let source = "(".repeat(100_000);
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new(&source, &mut chunk, &mut interner);
+        assert!(parser.advance().is_ok());
+        assert!(matches!(
+            parser.declaration(),
+            Err(ParserError::TooDeeplyNested(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_deeply_nested_statements() {
+        for source in [
+            "{".repeat(200_000),
+            "if (a) ".repeat(200_000),
+            "fun f() {".repeat(100_000),
+            "print fun () {".repeat(100_000),
+        ]
+        .iter()
+        {
+            let mut chunk = Chunk::default();
+            let mut interner = StringInterner::default();
+            let mut parser = Parser::new(source, &mut chunk, &mut interner);
+            assert!(parser.advance().is_ok());
+            let result = parser.declaration();
+            assert!(
+                matches!(result, Err(ParserError::TooDeeplyNested(_))),
+                "{:?} for {}",
+                result,
+                &source[..10]
+            );
+        }
+    }
+
+    #[test]
+    fn max_depth_is_configurable() {
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new("print ((1));", &mut chunk, &mut interner);
+        parser.set_max_depth(2);
+        assert!(parser.advance().is_ok());
+        assert!(matches!(
+            parser.declaration(),
+            Err(ParserError::TooDeeplyNested(_))
+        ));
+    }
+
+    #[test]
+    fn folds_number_arithmetic() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("print 2 * 3 + 1;", &mut interner).unwrap();
+
+        assert_eq!(chunk.len(), 4);
+        assert_eq!(chunk[0], OpCode::Constant as u8);
+        assert_eq!(chunk.constants().len(), 1);
+        let constant = chunk.get_constant(chunk[1] as usize);
+        assert!(matches!(constant, Some(Value::Number(n)) if *n == 7.0));
+
+        // Constants that are also loaded elsewhere stay in the pool.
+        let chunk = compile(
+            "print 2; print 2 + 2; var x = \"x\" + \"y\";",
+            &mut interner,
+        )
+        .unwrap();
+        let constants: Vec<String> = chunk
+            .constants()
+            .iter()
+            .map(|constant| constant.to_string(&interner))
+            .collect();
+        assert_eq!(constants, ["2", "4", "x", "xy"]);
+    }
+
+    #[test]
+    fn does_not_fold_non_finite_results() {
+        let mut interner = StringInterner::default();
+        let huge = format!("1{}", "0".repeat(308));
+        let source = format!("print {} * 10; print 1 / 0;", huge);
+        let chunk = compile(&source, &mut interner).unwrap();
+
+        assert_eq!(chunk[4], OpCode::Multiply as u8);
+        assert_eq!(chunk[10], OpCode::Divide as u8);
+    }
+
+    #[test]
+    fn reports_where_an_unclosed_paren_was_opened() {
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new("print (1 +\n(2)\n", &mut chunk, &mut interner);
+        assert!(parser.advance().is_ok());
+        match parser.declaration() {
+            Err(err @ ParserError::UnmatchedDelimiter(..)) => {
+                assert_eq!(
+                    err.to_string(),
+                    "[line 3] Error at end: Unmatched '(' opened at line 1"
+                )
+            }
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(()) => panic!("Expected an error"),
+        }
+    }
+
+    #[test]
+    fn locals_use_stack_slots() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("{ var a = 1; print a; }", &mut interner).unwrap();
+
+        let code: Vec<u8> = (0..chunk.len()).map(|i| chunk[i]).collect();
+        assert_eq!(
+            code,
+            [
+                OpCode::Constant as u8,
+                0,
+                OpCode::GetLocal as u8,
+                0,
+                OpCode::Print as u8,
+                OpCode::Pop as u8,
+                OpCode::Return as u8,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_local_declarations() {
+        for (source, expected) in [
+            ("{ var a = a; }", "in its own initializer"),
+            ("{ var a; var a; }", "with this name in this scope"),
+        ]
+        .iter()
+        {
+            let mut chunk = Chunk::default();
+            let mut interner = StringInterner::default();
+            let mut parser = Parser::new(source, &mut chunk, &mut interner);
+            assert!(parser.advance().is_ok());
+            match parser.declaration() {
+                Err(err) => assert!(err.to_string().ends_with(expected), "{}", err),
+                Ok(()) => panic!("Expected an error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_where_an_unclosed_brace_was_opened() {
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new("{\n  print 1;\n  { }\n", &mut chunk, &mut interner);
+        assert!(parser.advance().is_ok());
+        match parser.declaration() {
+            Err(err @ ParserError::UnmatchedDelimiter(..)) => assert_eq!(
+                err.to_string(),
+                "[line 4] Error at end: Unmatched '{' opened at line 1"
+            ),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(()) => panic!("Expected an error"),
+        }
+    }
+
+    #[test]
+    fn rejects_jumps_over_too_much_code() {
+        let source = format!("while (a) {{ {} }}", "print nil;".repeat(40_000));
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new(&source, &mut chunk, &mut interner);
+        assert!(parser.advance().is_ok());
+        assert!(matches!(
+            parser.declaration(),
+            Err(ParserError::JumpTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn folds_not_of_constants() {
+        for source in ["print !\"x\";", "print !\"\";", "print !0;"].iter() {
+            let mut interner = StringInterner::default();
+            let chunk = compile(source, &mut interner).unwrap();
+
+            let code: Vec<u8> = (0..chunk.len()).map(|i| chunk[i]).collect();
+            assert_eq!(
+                code,
+                [
+                    OpCode::False as u8,
+                    OpCode::Print as u8,
+                    OpCode::Return as u8
+                ],
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_return_outside_a_function() {
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new("return 1;", &mut chunk, &mut interner);
+        assert!(parser.advance().is_ok());
+        assert!(matches!(
+            parser.declaration(),
+            Err(ParserError::ReturnOutsideFunction(_))
+        ));
+    }
+
+    fn warnings_for(source: &str) -> Vec<ParserWarning> {
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let mut parser = Parser::new(source, &mut chunk, &mut interner);
+        assert!(parser.advance().is_ok());
+        while !parser.is_done() {
+            assert!(parser.declaration().is_ok(), "{}", source);
+        }
+        parser.warnings.drain(..).collect()
+    }
+
+    #[test]
+    fn warns_about_assignment_as_condition() {
+        assert_eq!(
+            warnings_for("var a;\nif (a = 1) print a;"),
+            [ParserWarning::AssignmentInCondition(2)]
+        );
+        assert_eq!(
+            warnings_for("var a;\nwhile (a = nil) print a;"),
+            [ParserWarning::AssignmentInCondition(2)]
+        );
+        assert_eq!(warnings_for("var a; if (a == 1) print a;"), []);
+        assert_eq!(warnings_for("var a; if ((a = 1)) print a;"), []);
+        assert_eq!(warnings_for("var a; var b; if (b or (a = 1)) print a;"), []);
+    }
+
+    #[test]
+    fn compile_returns_every_error() {
+        let mut interner = StringInterner::default();
+        let errors = compile("print 1 +; var = 2; print 3;", &mut interner).unwrap_err();
+
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], ParserError::ExpectExpression(_)));
+        assert!(matches!(errors[1], ParserError::UnexpectedToken(..)));
+    }
+
+    #[test]
+    fn rejects_more_than_255_arguments() {
+        let call = |count: usize| format!("f({});", vec!["nil"; count].join(", "));
+        let mut interner = StringInterner::default();
+
+        assert!(compile(&call(255), &mut interner).is_ok());
+        let errors = compile(&call(256), &mut interner).unwrap_err();
+        assert!(matches!(
+            errors.as_slice(),
+            [ParserError::TooManyArguments(_)]
+        ));
+    }
+
+    #[test]
+    fn prunes_branches_with_constant_conditions() {
+        let code_for = |source: &str| {
+            let mut interner = StringInterner::default();
+            let chunk = compile(source, &mut interner).unwrap();
+            let code: Vec<u8> = (0..chunk.len()).map(|i| chunk[i]).collect();
+            let constants: Vec<String> = chunk
+                .constants()
+                .iter()
+                .map(|constant| constant.to_string(&interner))
+                .collect();
+            (code, constants)
+        };
+        let print_constant = [
+            OpCode::Constant as u8,
+            0,
+            OpCode::Print as u8,
+            OpCode::Return as u8,
+        ];
+
+        assert_eq!(
+            code_for("if (false) print 1; else print 2;"),
+            (print_constant.to_vec(), vec!["2".to_string()])
+        );
+        assert_eq!(
+            code_for("if (1 + 1) print \"a\"; else print \"b\";"),
+            (print_constant.to_vec(), vec!["a".to_string()])
+        );
+        assert_eq!(
+            code_for("while (nil) print 1;"),
+            (vec![OpCode::Return as u8], vec![])
+        );
+        assert_eq!(
+            code_for("while (!false) print 1;"),
+            (
+                vec![
+                    OpCode::Constant as u8,
+                    0,
+                    OpCode::Print as u8,
+                    OpCode::Loop as u8,
+                    0,
+                    6,
+                    OpCode::Return as u8
+                ],
+                vec!["1".to_string()]
+            )
+        );
+
+        // The dropped branch is still compiled for its errors.
+        let mut interner = StringInterner::default();
+        assert!(compile("if (false) print 1 +;", &mut interner).is_err());
+    }
+
+    #[test]
+    fn rejects_this_outside_a_method() {
+        for source in ["print this;", "fun f() { return this; }"].iter() {
+            let mut interner = StringInterner::default();
+            match compile(source, &mut interner) {
+                Err(errors) => assert!(
+                    matches!(errors.first(), Some(ParserError::ThisOutsideMethod(_))),
+                    "{:?}",
+                    errors
+                ),
+                Ok(_) => panic!("Expected an error for {}", source),
+            }
+        }
+    }
+}
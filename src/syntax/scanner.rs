@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{borrow::Cow, fmt::Display};
 
 use super::token::{LiteralConstant, Token, TokenType};
 
@@ -7,8 +7,13 @@ pub struct Scanner<'a> {
     start: usize,
     current: usize,
     line: usize,
+    /// When set, unsupported characters produce `TokenType::Error` tokens and
+    /// are recorded in `errors` instead of failing the scan.
+    recover: bool,
+    errors: Vec<ScannerError>,
 }
 
+#[derive(Debug)]
 pub struct SourceErrContext {
     pub lexeme: String,
     pub line: usize,
@@ -26,10 +31,15 @@ impl SourceErrContext {
     }
 }
 
+#[derive(Debug)]
 pub enum ScannerError {
     UnexpectedEof(usize),
     UnsupportedChar(SourceErrContext, u8),
     InvalidNumber(SourceErrContext),
+    UnknownNumberSuffix(SourceErrContext),
+    NumberOverflow(SourceErrContext),
+    InvalidCharLiteral(SourceErrContext),
+    InvalidEscape(SourceErrContext),
 }
 
 impl Display for ScannerError {
@@ -40,12 +50,80 @@ impl Display for ScannerError {
                 write!(f, "{}: invalid char '{}'", ctx, *char as char)
             }
             ScannerError::InvalidNumber(ctx) => write!(f, "{}: invalid number", ctx),
+            ScannerError::UnknownNumberSuffix(ctx) => write!(f, "{}: unknown number suffix", ctx),
+            ScannerError::NumberOverflow(ctx) => write!(f, "{}: number is too large", ctx),
+            ScannerError::InvalidCharLiteral(ctx) => {
+                write!(f, "{}: char literal must be exactly one character", ctx)
+            }
+            ScannerError::InvalidEscape(ctx) => write!(f, "{}: invalid escape sequence", ctx),
         }
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum ByteClass {
+    Other,
+    Whitespace,
+    Digit,
+    Alpha,
+}
+
+/// Classifies every byte up front so the hot scanning loops do a single
+/// table lookup instead of a chain of range comparisons.
+static BYTE_CLASSES: [ByteClass; 256] = byte_classes();
+
+const fn byte_classes() -> [ByteClass; 256] {
+    let mut table = [ByteClass::Other; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        table[byte] = match byte as u8 {
+            b' ' | b'\r' | b'\t' => ByteClass::Whitespace,
+            b'0'..=b'9' => ByteClass::Digit,
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => ByteClass::Alpha,
+            _ => ByteClass::Other,
+        };
+        byte += 1;
+    }
+    table
+}
+
+fn byte_class(byte: u8) -> ByteClass {
+    BYTE_CLASSES[byte as usize]
+}
+
 fn is_digit(byte: u8) -> bool {
-    byte >= b'0' && byte <= b'9'
+    byte_class(byte) == ByteClass::Digit
+}
+
+fn is_alpha(byte: u8) -> bool {
+    byte_class(byte) == ByteClass::Alpha
+}
+
+fn is_alpha_numeric(byte: u8) -> bool {
+    matches!(byte_class(byte), ByteClass::Alpha | ByteClass::Digit)
+}
+
+/// Returns the character for the escape sequence `\<byte>`.
+fn escaped_char(byte: u8) -> Option<char> {
+    match byte {
+        b'n' => Some('\n'),
+        b't' => Some('\t'),
+        b'r' => Some('\r'),
+        b'0' => Some('\0'),
+        b'\\' => Some('\\'),
+        b'\'' => Some('\''),
+        b'"' => Some('"'),
+        _ => None,
+    }
+}
+
+/// Returns `token_type` if `lexeme` is exactly `keyword`, else `Identifier`.
+fn check_keyword(lexeme: &[u8], keyword: &str, token_type: TokenType) -> TokenType {
+    if lexeme == keyword.as_bytes() {
+        token_type
+    } else {
+        TokenType::Identifier
+    }
 }
 
 impl<'a> Scanner<'a> {
@@ -55,11 +133,27 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            recover: false,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Creates a scanner which skips over unsupported characters, collecting
+    /// the errors so a single pass can report all of them.
+    pub fn with_recovery(source: &'a str) -> Scanner<'a> {
+        Scanner {
+            recover: true,
+            ..Scanner::new(source)
         }
     }
 
+    /// Returns the errors skipped over in recovery mode.
+    pub fn errors(&self) -> &[ScannerError] {
+        &self.errors
+    }
+
     pub fn scan_token(&mut self) -> Result<Token<'a>, ScannerError> {
-        self.skip_whitespace_and_comments();
+        self.skip_whitespace_and_comments()?;
 
         self.start = self.current;
 
@@ -68,7 +162,7 @@ impl<'a> Scanner<'a> {
         // FIXME: To properly support utf-8 we'd need to support extended grapheme
         // clusters.
         if let Some(byte) = self.advance() {
-            return match byte {
+            match byte {
                 b'(' => Ok(self.make_token(LeftParen)),
                 b')' => Ok(self.make_token(RightParen)),
                 b'{' => Ok(self.make_token(LeftBrace)),
@@ -85,15 +179,21 @@ impl<'a> Scanner<'a> {
                 b'<' => Ok(self.make_match_token(b'=', LessEqual, Less)),
                 b'>' => Ok(self.make_match_token(b'=', GreaterEqual, Greater)),
                 b'"' => self.make_string(),
-                b'0'..=b'9' => self.make_number(),
-                b'a'..=b'z' | b'A'..=b'Z' => self.make_identifier(),
+                b'\'' => self.make_char(),
+                _ if is_digit(byte) => self.make_number(),
+                _ if is_alpha(byte) => self.make_identifier(),
                 _ => {
-                    let ctx = self.err_context();
-                    Err(ScannerError::UnsupportedChar(ctx, byte))
+                    let err = ScannerError::UnsupportedChar(self.err_context(), byte);
+                    if self.recover {
+                        self.errors.push(err);
+                        Ok(self.make_token(Error))
+                    } else {
+                        Err(err)
+                    }
                 }
-            };
+            }
         } else {
-            return Ok(self.make_token(TokenType::Eof));
+            Ok(self.make_token(TokenType::Eof))
         }
     }
 
@@ -128,7 +228,8 @@ impl<'a> Scanner<'a> {
     }
 
     fn make_string(&mut self) -> Result<Token<'a>, ScannerError> {
-        // Read until Eof or ".
+        // Read until Eof or an unescaped ".
+        let mut has_escapes = false;
         loop {
             match self.current_byte() {
                 Some(b'"') => {
@@ -137,6 +238,15 @@ impl<'a> Scanner<'a> {
                 None => {
                     break;
                 }
+                Some(b'\\') => {
+                    has_escapes = true;
+                    self.advance();
+                    // Skip the escaped byte, leaving newlines to the next
+                    // iteration so they are still counted.
+                    if self.current_byte().is_some_and(|byte| byte != b'\n') {
+                        self.advance();
+                    }
+                }
                 Some(b'\n') => {
                     self.line += 1;
                     self.advance();
@@ -148,12 +258,73 @@ impl<'a> Scanner<'a> {
         }
 
         if self.at_end() {
-            Err(ScannerError::UnexpectedEof(self.line))
+            return Err(ScannerError::UnexpectedEof(self.line));
+        }
+        self.advance(); // The closing quote.
+        let raw = &self.source[self.start + 1..self.current - 1];
+        let parsed_str = if has_escapes {
+            Cow::Owned(self.unescape(raw)?)
         } else {
-            self.advance(); // The closing quote.
-            let parsed_str = &self.source[self.start + 1..self.current - 1];
-            Ok(self.make_literal(TokenType::String, LiteralConstant::String(parsed_str)))
+            Cow::Borrowed(raw)
+        };
+        Ok(self.make_literal(TokenType::String, LiteralConstant::String(parsed_str)))
+    }
+
+    /// Replaces the escape sequences in a string literal's contents.
+    fn unescape(&self, raw: &str) -> Result<String, ScannerError> {
+        let mut unescaped = String::with_capacity(raw.len());
+        let mut rest = raw;
+        while let Some(idx) = rest.find('\\') {
+            unescaped.push_str(&rest[..idx]);
+            let escape = rest.as_bytes().get(idx + 1).copied();
+            match escape.and_then(escaped_char) {
+                Some(char) => unescaped.push(char),
+                None => return Err(ScannerError::InvalidEscape(self.err_context())),
+            }
+            rest = &rest[idx + 2..];
         }
+        unescaped.push_str(rest);
+        Ok(unescaped)
+    }
+
+    fn make_char(&mut self) -> Result<Token<'a>, ScannerError> {
+        // Read until the closing quote, which may not span lines.
+        loop {
+            match self.current_byte() {
+                Some(b'\'') | Some(b'\n') | None => break,
+                Some(b'\\') => {
+                    self.advance();
+                    // An escaped newline still ends the literal on this line.
+                    if !matches!(self.current_byte(), Some(b'\n') | None) {
+                        self.advance();
+                    }
+                }
+                Some(_) => {
+                    self.advance();
+                }
+            }
+        }
+        if self.current_byte() != Some(b'\'') {
+            return match self.current_byte() {
+                None => Err(ScannerError::UnexpectedEof(self.line)),
+                _ => Err(ScannerError::InvalidCharLiteral(self.err_context())),
+            };
+        }
+        self.advance(); // The closing quote.
+
+        let contents = &self.source[self.start + 1..self.current - 1];
+        let char = match contents.as_bytes() {
+            [b'\\', escape] => escaped_char(*escape)
+                .ok_or_else(|| ScannerError::InvalidEscape(self.err_context()))?,
+            _ => {
+                let mut chars = contents.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(char), None) if char != '\\' => char,
+                    _ => return Err(ScannerError::InvalidCharLiteral(self.err_context())),
+                }
+            }
+        };
+        Ok(self.make_literal(TokenType::Char, LiteralConstant::Char(char)))
     }
 
     fn make_number(&mut self) -> Result<Token<'a>, ScannerError> {
@@ -171,49 +342,94 @@ impl<'a> Scanner<'a> {
             }
         }
 
+        // Look for an exponent, leaving a bare "e" to start an identifier.
+        let at_exponent = matches!(self.current_byte(), Some(b'e') | Some(b'E'));
+        let exponent_len = self.exponent_len();
+        if exponent_len > 0 {
+            self.current += exponent_len;
+            while is_digit(self.current_byte().unwrap_or(0)) {
+                self.advance();
+            }
+        }
+
         let number_str = self.current_lexeme();
-        if let Ok(num) = number_str.parse::<f64>() {
-            Ok(self.make_literal(TokenType::Number, LiteralConstant::Number(num)))
-        } else {
-            Err(ScannerError::InvalidNumber(self.err_context()))
+        let num = match number_str.parse::<f64>() {
+            Ok(num) => num,
+            Err(_) => return Err(ScannerError::InvalidNumber(self.err_context())),
+        };
+
+        // Time unit suffixes are sugar for a number of seconds.
+        let suffix_start = self.current;
+        while !(at_exponent && exponent_len == 0) && is_alpha(self.current_byte().unwrap_or(0)) {
+            self.advance();
+        }
+        let num = match &self.source[suffix_start..self.current] {
+            "" | "s" => num,
+            "ms" => num / 1000.0,
+            "m" => num * 60.0,
+            "h" => num * 3600.0,
+            _ => return Err(ScannerError::UnknownNumberSuffix(self.err_context())),
+        };
+        // `parse` silently saturates to infinity rather than failing.
+        if !num.is_finite() {
+            return Err(ScannerError::NumberOverflow(self.err_context()));
+        }
+        Ok(self.make_literal(TokenType::Number, LiteralConstant::Number(num)))
+    }
+
+    /// Returns how many bytes of "e", optional sign and first digit start an
+    /// exponent at the current position, or 0 if there is no exponent.
+    fn exponent_len(&self) -> usize {
+        let bytes = &self.source.as_bytes()[self.current..];
+        match bytes {
+            [b'e', b'+', digit, ..] | [b'e', b'-', digit, ..] if is_digit(*digit) => 3,
+            [b'E', b'+', digit, ..] | [b'E', b'-', digit, ..] if is_digit(*digit) => 3,
+            [b'e', digit, ..] | [b'E', digit, ..] if is_digit(*digit) => 2,
+            _ => 0,
         }
     }
 
     fn make_identifier(&mut self) -> Result<Token<'a>, ScannerError> {
-        loop {
-            match self.current_byte().unwrap_or(0) {
-                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => {
-                    self.advance();
-                }
-                _ => {
-                    break;
-                }
-            }
+        while is_alpha_numeric(self.current_byte().unwrap_or(0)) {
+            self.advance();
         }
 
         Ok(self.make_token(self.identifier_type()))
     }
 
     fn identifier_type(&self) -> TokenType {
-        let identifier_str = self.current_lexeme();
-        match identifier_str {
-            "and" => TokenType::And,
-            "class" => TokenType::Class,
-            "else" => TokenType::Else,
-            "false" => TokenType::False,
-            "for" => TokenType::For,
-            "fun" => TokenType::Fun,
-            "if" => TokenType::If,
-            "nil" => TokenType::Nil,
-            "or" => TokenType::Or,
-            "print" => TokenType::Print,
-            "return" => TokenType::Return,
-            "super" => TokenType::Super,
-            "this" => TokenType::This,
-            "true" => TokenType::True,
-            "var" => TokenType::Var,
-            "while" => TokenType::While,
-            _ => TokenType::Identifier,
+        use TokenType::*;
+        let lexeme = self.current_lexeme().as_bytes();
+        // A small trie: branch on the leading bytes so at most one keyword
+        // needs a full comparison.
+        match lexeme[0] {
+            b'a' => check_keyword(lexeme, "and", And),
+            b'c' => check_keyword(lexeme, "class", Class),
+            b'e' => check_keyword(lexeme, "else", Else),
+            b'f' => match lexeme.get(1) {
+                Some(b'a') => check_keyword(lexeme, "false", False),
+                Some(b'o') => check_keyword(lexeme, "for", For),
+                Some(b'u') => check_keyword(lexeme, "fun", Fun),
+                _ => Identifier,
+            },
+            b'i' => check_keyword(lexeme, "if", If),
+            b'n' => check_keyword(lexeme, "nil", Nil),
+            b'o' => check_keyword(lexeme, "or", Or),
+            b'p' => check_keyword(lexeme, "print", Print),
+            b'r' => match lexeme.get(2) {
+                Some(b'p') => check_keyword(lexeme, "repeat", Repeat),
+                Some(b't') => check_keyword(lexeme, "return", Return),
+                _ => Identifier,
+            },
+            b's' => check_keyword(lexeme, "super", Super),
+            b't' => match lexeme.get(1) {
+                Some(b'h') => check_keyword(lexeme, "this", This),
+                Some(b'r') => check_keyword(lexeme, "true", True),
+                _ => Identifier,
+            },
+            b'v' => check_keyword(lexeme, "var", Var),
+            b'w' => check_keyword(lexeme, "while", While),
+            _ => Identifier,
         }
     }
 
@@ -230,7 +446,7 @@ impl<'a> Scanner<'a> {
                 return true;
             }
         }
-        return false;
+        false
     }
 
     /// Returns the current character byte, potentially partial utf-8.
@@ -256,20 +472,24 @@ impl<'a> Scanner<'a> {
         self.current >= self.source.len()
     }
 
-    fn skip_whitespace_and_comments(&mut self) {
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), ScannerError> {
         loop {
-            match self.current_byte().unwrap_or(0) {
-                b' ' | b'\r' | b'\t' => {
+            let byte = self.current_byte().unwrap_or(0);
+            match byte {
+                _ if byte_class(byte) == ByteClass::Whitespace => {
                     self.advance();
                 }
                 b'\n' => {
                     self.line += 1;
                     self.advance();
                 }
+                b'/' if self.peek_next_byte() == Some(b'*') => {
+                    self.skip_block_comment()?;
+                }
                 b'/' => {
                     let next_byte = self.peek_next_byte();
                     if next_byte != Some(b'/') {
-                        return;
+                        return Ok(());
                     }
                     self.advance();
                     // A comment goes until the end of the line or Eof.
@@ -288,11 +508,35 @@ impl<'a> Scanner<'a> {
                     }
                 }
                 _ => {
-                    return;
+                    return Ok(());
                 }
             }
         }
     }
+
+    /// Skips a `/* ... */` comment, which may nest.
+    fn skip_block_comment(&mut self) -> Result<(), ScannerError> {
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            match self.current_byte() {
+                Some(b'/') if self.peek_next_byte() == Some(b'*') => {
+                    self.advance();
+                    depth += 1;
+                }
+                Some(b'*') if self.peek_next_byte() == Some(b'/') => {
+                    self.advance();
+                    depth -= 1;
+                }
+                Some(b'\n') => self.line += 1,
+                Some(_) => {}
+                None => return Err(ScannerError::UnexpectedEof(self.line)),
+            }
+            self.advance();
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -307,6 +551,16 @@ mod tests {
         }
     }
 
+    fn check_number(scanner: &mut Scanner, expected: f64) {
+        match scanner.scan_token() {
+            Ok(token) => match token.literal() {
+                LiteralConstant::Number(num) => assert_eq!(*num, expected),
+                other => panic!("Unexpected literal: {:?}", other),
+            },
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+
     #[test]
     fn scans_token_types() {
         let mut scanner = Scanner::new("var a = \"hi\" or 5; if (a) { print a; }");
@@ -328,4 +582,271 @@ mod tests {
         check_type(&mut scanner, TokenType::RightBrace);
         check_type(&mut scanner, TokenType::Eof);
     }
+
+    #[test]
+    fn identifiers_may_contain_underscores() {
+        let mut scanner = Scanner::new("_ is_int var_");
+        for _ in 0..3 {
+            check_type(&mut scanner, TokenType::Identifier);
+        }
+        check_type(&mut scanner, TokenType::Eof);
+    }
+
+    #[test]
+    fn recovers_from_unsupported_chars() {
+        let mut scanner = Scanner::with_recovery("var a @ = 1 @ ;");
+        check_type(&mut scanner, TokenType::Var);
+        check_type(&mut scanner, TokenType::Identifier);
+        check_type(&mut scanner, TokenType::Error);
+        check_type(&mut scanner, TokenType::Equal);
+        check_type(&mut scanner, TokenType::Number);
+        check_type(&mut scanner, TokenType::Error);
+        check_type(&mut scanner, TokenType::Semicolon);
+        check_type(&mut scanner, TokenType::Eof);
+
+        let errors: Vec<String> = scanner.errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "[line 1] Error at '@': invalid char '@'",
+                "[line 1] Error at '@': invalid char '@'",
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_time_suffixes_as_seconds() {
+        let mut scanner = Scanner::new("5s 100ms 2m 1.5h 7");
+        check_number(&mut scanner, 5.0);
+        check_number(&mut scanner, 0.1);
+        check_number(&mut scanner, 120.0);
+        check_number(&mut scanner, 5400.0);
+        check_number(&mut scanner, 7.0);
+        check_type(&mut scanner, TokenType::Eof);
+    }
+
+    #[test]
+    fn rejects_unknown_number_suffix() {
+        let mut scanner = Scanner::new("5parsecs");
+        match scanner.scan_token() {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "[line 1] Error at '5parsecs': unknown number suffix"
+            ),
+            Ok(token) => panic!("Unexpected token: {:?}", token),
+        }
+    }
+
+    #[test]
+    fn rejects_number_overflow() {
+        let huge = format!("1{}", "0".repeat(400));
+        let mut scanner = Scanner::new(&huge);
+        match scanner.scan_token() {
+            Err(ScannerError::NumberOverflow(ctx)) => assert_eq!(ctx.lexeme, huge),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(token) => panic!("Unexpected token: {:?}", token),
+        }
+
+        let mut scanner = Scanner::new("1000000000");
+        check_number(&mut scanner, 1e9);
+    }
+
+    #[test]
+    fn scans_whole_tokens() {
+        let mut scanner = Scanner::new("var a =\n\"hi\" + 1.5;");
+        let mut tokens = Vec::new();
+        loop {
+            let token = match scanner.scan_token() {
+                Ok(token) => token,
+                Err(err) => panic!("Unexpected error: {}", err),
+            };
+            let done = *token.token_type() == TokenType::Eof;
+            tokens.push(token);
+            if done {
+                break;
+            }
+        }
+
+        use LiteralConstant as L;
+        use TokenType as T;
+        let expected = vec![
+            Token::new(T::Var, "var", L::None, 1),
+            Token::new(T::Identifier, "a", L::None, 1),
+            Token::new(T::Equal, "=", L::None, 1),
+            Token::new(T::String, "\"hi\"", L::String("hi".into()), 2),
+            Token::new(T::Plus, "+", L::None, 2),
+            Token::new(T::Number, "1.5", L::Number(1.5), 2),
+            Token::new(T::Semicolon, ";", L::None, 2),
+            Token::new(T::Eof, "", L::None, 2),
+        ];
+        assert_eq!(tokens, expected);
+    }
+
+    #[test]
+    fn number_literals_compare_by_bits() {
+        let nan = Token::new(TokenType::Number, "x", LiteralConstant::Number(f64::NAN), 1);
+        assert_eq!(nan, nan.clone());
+
+        let zero = Token::new(TokenType::Number, "0", LiteralConstant::Number(0.0), 1);
+        let neg_zero = Token::new(TokenType::Number, "0", LiteralConstant::Number(-0.0), 1);
+        assert_ne!(zero, neg_zero);
+    }
+
+    #[test]
+    fn scans_keywords_and_near_misses() {
+        let keywords = [
+            ("and", TokenType::And),
+            ("class", TokenType::Class),
+            ("else", TokenType::Else),
+            ("false", TokenType::False),
+            ("for", TokenType::For),
+            ("fun", TokenType::Fun),
+            ("if", TokenType::If),
+            ("nil", TokenType::Nil),
+            ("or", TokenType::Or),
+            ("print", TokenType::Print),
+            ("repeat", TokenType::Repeat),
+            ("return", TokenType::Return),
+            ("super", TokenType::Super),
+            ("this", TokenType::This),
+            ("true", TokenType::True),
+            ("var", TokenType::Var),
+            ("while", TokenType::While),
+        ];
+        for (keyword, token_type) in keywords.iter() {
+            check_type(&mut Scanner::new(keyword), *token_type);
+            let longer = format!("{}x", keyword);
+            check_type(&mut Scanner::new(&longer), TokenType::Identifier);
+            check_type(&mut Scanner::new(&keyword[..1]), TokenType::Identifier);
+        }
+        for identifier in ["f", "fa", "t", "th", "tr", "re", "rex", "x", "Var", "v4r"].iter() {
+            check_type(&mut Scanner::new(identifier), TokenType::Identifier);
+        }
+    }
+
+    #[test]
+    fn scans_char_literals() {
+        for (source, expected) in
+            [("'a'", 'a'), ("'\\n'", '\n'), ("'\\''", '\''), ("'é'", 'é')].iter()
+        {
+            match Scanner::new(source).scan_token() {
+                Ok(token) => assert_eq!(*token.literal(), LiteralConstant::Char(*expected)),
+                Err(err) => panic!("Unexpected error: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_char_literals() {
+        for source in ["'ab'", "''", "'a\n'"].iter() {
+            let result = Scanner::new(source).scan_token();
+            assert!(
+                matches!(result, Err(ScannerError::InvalidCharLiteral(_))),
+                "{}",
+                source
+            );
+        }
+        assert!(matches!(
+            Scanner::new("'\\q'").scan_token(),
+            Err(ScannerError::InvalidEscape(_))
+        ));
+        assert!(matches!(
+            Scanner::new("'a").scan_token(),
+            Err(ScannerError::UnexpectedEof(_))
+        ));
+    }
+
+    #[test]
+    fn counts_the_newline_after_a_trailing_char_escape() {
+        let mut scanner = Scanner::new("'\\\nx");
+        assert!(matches!(
+            scanner.scan_token(),
+            Err(ScannerError::InvalidCharLiteral(_))
+        ));
+        match scanner.scan_token() {
+            Ok(token) => {
+                assert_eq!(*token.token_type(), TokenType::Identifier);
+                assert_eq!(token.line(), 2);
+            }
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn scans_string_escapes() {
+        let cases = [
+            ("\"a\\nb\"", "a\nb"),
+            ("\"a\\tb\"", "a\tb"),
+            ("\"a\\rb\"", "a\rb"),
+            ("\"a\\\\b\"", "a\\b"),
+            ("\"a\\\"b\"", "a\"b"),
+            ("\"a\\0b\"", "a\0b"),
+            ("\"plain\"", "plain"),
+        ];
+        for (source, expected) in cases.iter() {
+            match Scanner::new(source).scan_token() {
+                Ok(token) => assert_eq!(
+                    *token.literal(),
+                    LiteralConstant::String((*expected).into()),
+                    "{}",
+                    source
+                ),
+                Err(err) => panic!("Unexpected error: {}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_string_escapes() {
+        assert!(matches!(
+            Scanner::new("\"a\\qb\"").scan_token(),
+            Err(ScannerError::InvalidEscape(_))
+        ));
+    }
+
+    #[test]
+    fn skips_block_comments() {
+        let mut scanner = Scanner::new("1 /* a\nb */ + /* /* nested */ */ 2 /*\n*/;");
+        for expected in [TokenType::Number, TokenType::Plus, TokenType::Number].iter() {
+            check_type(&mut scanner, *expected);
+        }
+        match scanner.scan_token() {
+            Ok(token) => assert_eq!(token.line(), 3),
+            Err(err) => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn rejects_unterminated_block_comments() {
+        for source in ["/* a", "/* /* a */"].iter() {
+            assert!(
+                matches!(
+                    Scanner::new(source).scan_token(),
+                    Err(ScannerError::UnexpectedEof(1))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn scans_scientific_notation() {
+        let mut scanner = Scanner::new("1e3 1.5E-2 2e+5");
+        check_number(&mut scanner, 1000.0);
+        check_number(&mut scanner, 0.015);
+        check_number(&mut scanner, 200000.0);
+        check_type(&mut scanner, TokenType::Eof);
+    }
+
+    #[test]
+    fn leaves_a_bare_exponent_as_an_identifier() {
+        let mut scanner = Scanner::new("3e 4E+");
+        check_number(&mut scanner, 3.0);
+        check_type(&mut scanner, TokenType::Identifier);
+        check_number(&mut scanner, 4.0);
+        check_type(&mut scanner, TokenType::Identifier);
+        check_type(&mut scanner, TokenType::Plus);
+        check_type(&mut scanner, TokenType::Eof);
+    }
 }
@@ -1,8 +1,14 @@
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    hash::{Hash, Hasher},
+};
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum TokenType {
     Eof,
+    // An unsupported character skipped by a recovering `Scanner`.
+    Error,
 
     // Single-character tokens.
     LeftParen,
@@ -31,6 +37,7 @@ pub enum TokenType {
     Identifier,
     String,
     Number,
+    Char,
 
     // Keywords.
     And,
@@ -43,6 +50,7 @@ pub enum TokenType {
     Nil,
     Or,
     Print,
+    Repeat,
     Return,
     Super,
     This,
@@ -51,16 +59,47 @@ pub enum TokenType {
     While,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// String literals borrow from the source unless they contain escapes.
+#[derive(Clone, Debug)]
 pub enum LiteralConstant<'a> {
     None,
-    String(&'a str),
+    String(Cow<'a, str>),
     Number(f64),
+    Char(char),
+}
+
+// Numbers compare by bit pattern so equality is reflexive and consistent
+// with `Hash`, even for `NaN`.
+impl PartialEq for LiteralConstant<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        use LiteralConstant::*;
+        match (self, other) {
+            (None, None) => true,
+            (String(a), String(b)) => a == b,
+            (Number(a), Number(b)) => a.to_bits() == b.to_bits(),
+            (Char(a), Char(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for LiteralConstant<'_> {}
+
+impl Hash for LiteralConstant<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            LiteralConstant::None => {}
+            LiteralConstant::String(str) => str.hash(state),
+            LiteralConstant::Number(num) => num.to_bits().hash(state),
+            LiteralConstant::Char(char) => char.hash(state),
+        }
+    }
 }
 
 pub type Line = usize;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Token<'a> {
     token_type: TokenType,
     lexeme: &'a str,
@@ -69,6 +108,7 @@ pub struct Token<'a> {
 }
 
 // For error messages.
+#[derive(Debug)]
 pub struct TokenErrContext {
     pub token_type: TokenType,
     pub lexeme: String,
@@ -111,12 +151,12 @@ impl<'a> Token<'a> {
         &self.token_type
     }
 
-    pub fn lexeme(&self) -> &str {
+    pub fn lexeme(&self) -> &'a str {
         self.lexeme
     }
 
-    pub fn literal(&self) -> LiteralConstant<'a> {
-        self.literal
+    pub fn literal(&self) -> &LiteralConstant<'a> {
+        &self.literal
     }
 
     pub fn line(&self) -> Line {
@@ -1,24 +1,36 @@
-use std::{convert::TryFrom, ops::Index, usize};
-use string_interner::StringInterner;
+use std::{collections::HashMap, convert::TryFrom, mem::size_of, ops::Index};
+use string_interner::{DefaultSymbol, StringInterner};
 
-use super::value::Value;
+use super::{disassembler::instruction_texts, value::Value};
 
 pub type Offset = usize;
 
 /// `OpCode` or data.
 pub type ByteCode = u8;
 
+/// The constant pool is indexed by one operand byte, or by three for the long
+/// forms such as `OpCode::ConstantLong`.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Nil,
     True,
     False,
     Pop,
+    GetLocal,
+    SetLocal,
     GetGlobal,
+    GetGlobalLong,
     DefineGlobal,
+    DefineGlobalLong,
     SetGlobal,
+    SetGlobalLong,
+    GetProperty,
+    GetPropertyLong,
     Equal,
     Greater,
     Less,
@@ -29,6 +41,13 @@ pub enum OpCode {
     Not,
     Negate,
     Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    /// Calls a native by its index in the VM's registry, without looking up
+    /// its name. Operands are the index and the argument count.
+    CallNative,
     Return,
 }
 
@@ -42,13 +61,21 @@ impl TryFrom<ByteCode> for OpCode {
         use OpCode::*;
         match v {
             x if x == Constant as ByteCode => Ok(Constant),
+            x if x == ConstantLong as ByteCode => Ok(ConstantLong),
             x if x == Nil as ByteCode => Ok(Nil),
             x if x == True as ByteCode => Ok(True),
             x if x == False as ByteCode => Ok(False),
             x if x == Pop as ByteCode => Ok(Pop),
+            x if x == GetLocal as ByteCode => Ok(GetLocal),
+            x if x == SetLocal as ByteCode => Ok(SetLocal),
             x if x == GetGlobal as ByteCode => Ok(GetGlobal),
+            x if x == GetGlobalLong as ByteCode => Ok(GetGlobalLong),
             x if x == DefineGlobal as ByteCode => Ok(DefineGlobal),
+            x if x == DefineGlobalLong as ByteCode => Ok(DefineGlobalLong),
             x if x == SetGlobal as ByteCode => Ok(SetGlobal),
+            x if x == SetGlobalLong as ByteCode => Ok(SetGlobalLong),
+            x if x == GetProperty as ByteCode => Ok(GetProperty),
+            x if x == GetPropertyLong as ByteCode => Ok(GetPropertyLong),
             x if x == Equal as ByteCode => Ok(Equal),
             x if x == Greater as ByteCode => Ok(Greater),
             x if x == Less as ByteCode => Ok(Less),
@@ -59,49 +86,189 @@ impl TryFrom<ByteCode> for OpCode {
             x if x == Not as ByteCode => Ok(Not),
             x if x == Negate as ByteCode => Ok(Negate),
             x if x == Print as ByteCode => Ok(Print),
+            x if x == Jump as ByteCode => Ok(Jump),
+            x if x == JumpIfFalse as ByteCode => Ok(JumpIfFalse),
+            x if x == Loop as ByteCode => Ok(Loop),
+            x if x == Call as ByteCode => Ok(Call),
+            x if x == CallNative as ByteCode => Ok(CallNative),
             x if x == Return as ByteCode => Ok(Return),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Default)]
+impl OpCode {
+    /// Returns the number of operand bytes following the opcode.
+    pub fn operand_len(self) -> usize {
+        use OpCode::*;
+        match self {
+            Constant | GetLocal | SetLocal | GetGlobal | DefineGlobal | SetGlobal | GetProperty
+            | Call => 1,
+            Jump | JumpIfFalse | Loop | CallNative => 2,
+            ConstantLong | GetGlobalLong | DefineGlobalLong | SetGlobalLong | GetPropertyLong => 3,
+            _ => 0,
+        }
+    }
+
+    /// Returns the variant of an opcode with a constant pool operand that
+    /// takes a three-byte index, if it has one.
+    pub fn long_form(self) -> Option<OpCode> {
+        use OpCode::*;
+        match self {
+            Constant => Some(ConstantLong),
+            GetGlobal => Some(GetGlobalLong),
+            DefineGlobal => Some(DefineGlobalLong),
+            SetGlobal => Some(SetGlobalLong),
+            GetProperty => Some(GetPropertyLong),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the opcode's operand indexes the constant pool.
+    pub fn has_constant_operand(self) -> bool {
+        self.long_form().is_some() || self.is_long()
+    }
+
+    /// Returns whether the opcode takes a three-byte constant pool index.
+    pub fn is_long(self) -> bool {
+        use OpCode::*;
+        matches!(
+            self,
+            ConstantLong | GetGlobalLong | DefineGlobalLong | SetGlobalLong | GetPropertyLong
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Chunk {
     code: Vec<ByteCode>,
-    // FIXME: this representation is wasteful, see Chapter 14, challenge 1.
-    lines: Vec<usize>,
+    /// Source lines, run-length encoded as the offset where each run of
+    /// bytes from the same line starts and that line. Offsets are
+    /// increasing, so a line can be found by binary search.
+    lines: Vec<(Offset, usize)>,
     constants: Vec<Value>,
+    /// Index of each constant in the pool, so repeated constants share a slot.
+    constant_indices: HashMap<ConstantKey, usize>,
+    /// How many constants were added by reusing an existing slot.
+    deduplicated: usize,
+}
+
+/// Identifies constants that can share a slot. Numbers compare by bit
+/// pattern: `0.0` and `-0.0` behave differently and stay apart, while `NaN`s
+/// with the same bits are interchangeable.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(DefaultSymbol),
+    Char(char),
+}
+
+impl ConstantKey {
+    /// Returns `None` for values that are never shared, such as functions.
+    fn for_value(value: &Value) -> Option<ConstantKey> {
+        match value {
+            Value::Number(num) => Some(ConstantKey::Number(num.to_bits())),
+            Value::InternedString(symbol) => Some(ConstantKey::String(*symbol)),
+            Value::Char(char) => Some(ConstantKey::Char(*char)),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) enum ChunkConstant<'a> {
     Number(f64),
     String(&'a str),
+    Char(char),
 }
 
 impl Chunk {
     pub fn write(&mut self, instr: ByteCode, line: usize) {
+        if self.lines.last().map(|(_, last)| *last) != Some(line) {
+            self.lines.push((self.code.len(), line));
+        }
         self.code.push(instr);
-        self.lines.push(line);
     }
 
     pub fn len(&self) -> usize {
         self.code.len()
     }
 
-    // FIXME: Chapter 21 challenge 1: avoid creating a new constant if we've
-    // added the same constant previously.
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Releases any excess capacity once the chunk is done being written to.
+    pub fn finalize(&mut self) {
+        self.code.shrink_to_fit();
+        self.lines.shrink_to_fit();
+        self.constants.shrink_to_fit();
+        self.constant_indices.shrink_to_fit();
+    }
+
+    /// Returns the number of bytes allocated for this chunk's buffers.
+    pub fn memory_usage(&self) -> usize {
+        self.code.capacity() * size_of::<ByteCode>()
+            + self.lines.capacity() * size_of::<(Offset, usize)>()
+            + self.constants.capacity() * size_of::<Value>()
+            + self.constant_indices.capacity() * size_of::<(ConstantKey, usize)>()
+    }
+
+    /// Overwrites an already written byte, used to backpatch jump offsets.
+    pub(crate) fn patch(&mut self, offset: Offset, byte: ByteCode) {
+        self.code[offset] = byte;
+    }
+
+    /// Drops all bytecode from `len` onwards. Constants are left untouched.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        let runs = self.lines.partition_point(|(start, _)| *start < len);
+        self.lines.truncate(runs);
+    }
+
     pub(crate) fn add_constant(
         &mut self,
         interner: &mut StringInterner,
         constant: ChunkConstant,
-    ) -> Option<ByteCode> {
+    ) -> Option<usize> {
+        let value = self.value_for_constant(interner, constant);
+        self.add_value(value)
+    }
+
+    /// Adds an already-built value to the constant pool, reusing the slot of
+    /// an identical constant added before. Returns `None` once the pool is
+    /// full.
+    pub(crate) fn add_value(&mut self, value: Value) -> Option<usize> {
+        let key = ConstantKey::for_value(&value);
+        let existing = key.as_ref().and_then(|key| self.constant_indices.get(key));
+        if let Some(idx) = existing.copied() {
+            self.deduplicated += 1;
+            return Some(idx);
+        }
         let constant_idx = self.constants.len();
-        if let Ok(bytecode_idx) = ByteCode::try_from(constant_idx) {
-            let value = self.value_for_constant(interner, constant);
-            self.constants.push(value);
-            Some(bytecode_idx)
-        } else {
-            None
+        if constant_idx >= MAX_CONSTANTS {
+            return None;
+        }
+        self.constants.push(value);
+        if let Some(key) = key {
+            self.constant_indices.insert(key, constant_idx);
+        }
+        Some(constant_idx)
+    }
+
+    /// Removes the last constant from the pool. The caller must have removed
+    /// all code that loads it.
+    pub(crate) fn pop_constant(&mut self) {
+        if let Some(value) = self.constants.pop() {
+            if let Some(key) = ConstantKey::for_value(&value) {
+                self.constant_indices.remove(&key);
+            }
+        }
+    }
+
+    /// Removes constants from the end of the pool until `len` are left.
+    pub(crate) fn truncate_constants(&mut self, len: usize) {
+        while self.constants.len() > len {
+            self.pop_constant();
         }
     }
 
@@ -113,25 +280,387 @@ impl Chunk {
         match constant {
             ChunkConstant::Number(num) => Value::Number(num),
             ChunkConstant::String(str) => Value::InternedString(interner.get_or_intern(str)),
+            ChunkConstant::Char(char) => Value::Char(char),
         }
     }
 
-    pub fn get_constant(&self, offset: ByteCode) -> Option<&Value> {
-        self.constants.get(offset as usize)
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    /// Returns how many constant pool slots were saved by reusing the slot of
+    /// an identical constant.
+    pub fn deduplicated_constants(&self) -> usize {
+        self.deduplicated
+    }
+
+    pub fn get_constant(&self, idx: usize) -> Option<&Value> {
+        self.constants.get(idx)
+    }
+
+    /// Returns the constant pool index operand of the instruction at
+    /// `offset`, which is three bytes wide for the long forms.
+    pub fn constant_index(&self, offset: Offset) -> Option<usize> {
+        match OpCode::try_from(*self.code.get(offset)?) {
+            Ok(opcode) if opcode.is_long() => {
+                let bytes = self.code.get(offset + 1..offset + 4)?;
+                Some(bytes.iter().fold(0, |idx, byte| idx << 8 | *byte as usize))
+            }
+            _ => self.code.get(offset + 1).map(|idx| *idx as usize),
+        }
+    }
+
+    /// Returns the symbols of all interned string constants, in pool order.
+    pub fn constant_symbols(&self) -> Vec<DefaultSymbol> {
+        self.constants
+            .iter()
+            .filter_map(|value| match value {
+                Value::InternedString(symbol) => Some(*symbol),
+                _ => None,
+            })
+            .collect()
     }
 
     pub fn get_bytecode(&self, offset: usize) -> Option<&ByteCode> {
         self.code.get(offset)
     }
 
+    /// Decodes the chunk one instruction at a time.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset: 0,
+        }
+    }
+
+    /// Returns whether both chunks hold the same code and constants, ignoring
+    /// line numbers. Each chunk's strings are resolved through its own
+    /// interner, so chunks compiled separately can be compared.
+    pub fn bytecode_eq(
+        &self,
+        interner: &StringInterner,
+        other: &Chunk,
+        other_interner: &StringInterner,
+    ) -> bool {
+        self.code == other.code
+            && self.constants.len() == other.constants.len()
+            && self
+                .constants
+                .iter()
+                .zip(&other.constants)
+                .all(|(a, b)| constant_eq(a, interner, b, other_interner))
+    }
+
+    /// Lists the instructions of both chunks, marking those only in `self`
+    /// with `-` and those only in `other` with `+`, for explaining a failed
+    /// `bytecode_eq`. Instructions are aligned along their longest common
+    /// subsequence, so an inserted instruction shows up on its own.
+    pub fn diff(
+        &self,
+        interner: &StringInterner,
+        other: &Chunk,
+        other_interner: &StringInterner,
+    ) -> String {
+        let ours = instruction_texts(self, interner);
+        let theirs = instruction_texts(other, other_interner);
+
+        // `common[i][j]` is the length of the longest common subsequence of
+        // `ours[i..]` and `theirs[j..]`.
+        let mut common = vec![vec![0; theirs.len() + 1]; ours.len() + 1];
+        for i in (0..ours.len()).rev() {
+            for j in (0..theirs.len()).rev() {
+                common[i][j] = if ours[i] == theirs[j] {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    common[i + 1][j].max(common[i][j + 1])
+                };
+            }
+        }
+
+        let mut diff = String::new();
+        let (mut i, mut j) = (0, 0);
+        while i < ours.len() || j < theirs.len() {
+            if i < ours.len() && j < theirs.len() && ours[i] == theirs[j] {
+                diff.push_str(&format!("  {}\n", ours[i]));
+                i += 1;
+                j += 1;
+            } else if j == theirs.len() || (i < ours.len() && common[i + 1][j] >= common[i][j + 1])
+            {
+                diff.push_str(&format!("- {}\n", ours[i]));
+                i += 1;
+            } else {
+                diff.push_str(&format!("+ {}\n", theirs[j]));
+                j += 1;
+            }
+        }
+        diff
+    }
+
+    /// Returns the source line of the byte at `offset`, or 0 if it is past
+    /// the end of the chunk.
     pub fn get_line(&self, offset: usize) -> usize {
-        *self.lines.get(offset).unwrap_or(&0)
+        if offset >= self.code.len() {
+            return 0;
+        }
+        let run = self.lines.partition_point(|(start, _)| *start <= offset);
+        self.lines[run - 1].1
+    }
+}
+
+/// Numbers compare by bit pattern so that a `NaN` constant equals itself,
+/// strings and function names by their text, and functions by their compiled
+/// code.
+fn constant_eq(
+    a: &Value,
+    a_interner: &StringInterner,
+    b: &Value,
+    b_interner: &StringInterner,
+) -> bool {
+    let resolve_eq = |a: Option<DefaultSymbol>, b: Option<DefaultSymbol>| {
+        a.map(|a| a_interner.resolve(a)) == b.map(|b| b_interner.resolve(b))
+    };
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+        (Value::InternedString(a), Value::InternedString(b)) => resolve_eq(Some(*a), Some(*b)),
+        (Value::Function(a), Value::Function(b)) => {
+            a.arity() == b.arity()
+                && resolve_eq(a.name(), b.name())
+                && a.chunk().bytecode_eq(a_interner, b.chunk(), b_interner)
+        }
+        _ => a.equal(b),
+    }
+}
+
+/// An instruction decoded by `Chunk::instructions`.
+#[derive(Debug)]
+pub struct Instruction<'a> {
+    pub offset: Offset,
+    /// The opcode, or the byte found in its place if that isn't one.
+    pub opcode: Result<OpCode, ByteCode>,
+    /// The operand bytes, cut short if the chunk ends first.
+    pub operands: &'a [ByteCode],
+    /// The constant the operand refers to, for opcodes that index the pool.
+    pub constant: Option<&'a Value>,
+}
+
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: Offset,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Instruction<'a>;
+
+    fn next(&mut self) -> Option<Instruction<'a>> {
+        let offset = self.offset;
+        let byte = *self.chunk.code.get(offset)?;
+        let opcode = OpCode::try_from(byte).map_err(|()| byte);
+        let operand_len = opcode.map_or(0, OpCode::operand_len);
+        let end = self.chunk.code.len().min(offset + 1 + operand_len);
+        let constant = match opcode {
+            Ok(opcode) if opcode.has_constant_operand() => self
+                .chunk
+                .constant_index(offset)
+                .and_then(|idx| self.chunk.get_constant(idx)),
+            _ => None,
+        };
+        self.offset = offset + 1 + operand_len;
+        Some(Instruction {
+            offset,
+            opcode,
+            operands: &self.chunk.code[offset + 1..end],
+            constant,
+        })
     }
 }
 
 impl Index<usize> for Chunk {
     type Output = ByteCode;
-    fn index<'a>(&'a self, idx: usize) -> &'a ByteCode {
+    fn index(&self, idx: usize) -> &ByteCode {
         &self.code[idx]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::compiler::compile;
+
+    #[test]
+    fn finalize_releases_excess_capacity() {
+        let mut interner = StringInterner::default();
+        let mut chunk = Chunk::default();
+        for i in 0..10 {
+            let idx = chunk
+                .add_constant(&mut interner, ChunkConstant::Number(i as f64))
+                .unwrap();
+            chunk.write(OpCode::Constant as ByteCode, 1);
+            chunk.write(idx as ByteCode, 1);
+            chunk.write(OpCode::Pop as ByteCode, 1);
+        }
+        chunk.write(OpCode::Return as ByteCode, 2);
+
+        let before = chunk.memory_usage();
+        chunk.finalize();
+        let after = chunk.memory_usage();
+        assert!(after < before, "{} should be less than {}", after, before);
+        // The slot index for deduplication is counted too.
+        let index = chunk.constant_indices.capacity() * size_of::<(ConstantKey, usize)>();
+        assert!(index > 0 && after > index);
+        assert_eq!(chunk.len(), 31);
+        assert_eq!(chunk.get_line(30), 2);
+        assert!(matches!(chunk.get_constant(9), Some(Value::Number(n)) if *n == 9.0));
+    }
+
+    #[test]
+    fn identical_string_literals_share_a_symbol() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("print \"a\" == \"a\"; print \"b\";", &mut interner).unwrap();
+
+        let symbols = chunk.constant_symbols();
+        assert_eq!(symbols.len(), 2);
+        assert_ne!(symbols[0], symbols[1]);
+        assert_eq!(interner.resolve(symbols[0]), Some("a"));
+        assert_eq!(interner.resolve(symbols[1]), Some("b"));
+    }
+
+    #[test]
+    fn identical_constants_share_a_slot() {
+        let mut interner = StringInterner::default();
+        let mut chunk = Chunk::default();
+        let mut add = |constant| chunk.add_constant(&mut interner, constant).unwrap();
+
+        let one = add(ChunkConstant::Number(1.0));
+        assert_eq!(add(ChunkConstant::Number(1.0)), one);
+        assert_ne!(add(ChunkConstant::Number(2.0)), one);
+        let zero = add(ChunkConstant::Number(0.0));
+        assert_ne!(add(ChunkConstant::Number(-0.0)), zero);
+        let nan = add(ChunkConstant::Number(f64::NAN));
+        assert_eq!(add(ChunkConstant::Number(f64::NAN)), nan);
+        let a = add(ChunkConstant::String("a"));
+        assert_eq!(add(ChunkConstant::String("a")), a);
+        assert_ne!(add(ChunkConstant::String("b")), a);
+        let char = add(ChunkConstant::Char('a'));
+        assert_ne!(char, a);
+        assert_eq!(add(ChunkConstant::Char('a')), char);
+        assert_eq!(chunk.constants().len(), 8);
+    }
+
+    fn all_lines(chunk: &Chunk) -> Vec<usize> {
+        (0..chunk.len())
+            .map(|offset| chunk.get_line(offset))
+            .collect()
+    }
+
+    #[test]
+    fn lines_of_a_single_line_chunk() {
+        let mut chunk = Chunk::default();
+        for _ in 0..100 {
+            chunk.write(OpCode::Nil as ByteCode, 7);
+        }
+
+        assert_eq!(chunk.lines.len(), 1);
+        assert_eq!(chunk.get_line(0), 7);
+        assert_eq!(chunk.get_line(99), 7);
+        assert_eq!(chunk.get_line(100), 0);
+    }
+
+    #[test]
+    fn lines_across_run_boundaries() {
+        let mut chunk = Chunk::default();
+        for (count, line) in [(2, 1), (3, 2), (1, 5), (2, 2)].iter() {
+            for _ in 0..*count {
+                chunk.write(OpCode::Nil as ByteCode, *line);
+            }
+        }
+
+        assert_eq!(all_lines(&chunk), [1, 1, 2, 2, 2, 5, 2, 2]);
+        assert_eq!(chunk.lines.len(), 4);
+
+        // Truncating mid-run keeps the start of that run.
+        chunk.truncate(4);
+        chunk.write(OpCode::Nil as ByteCode, 2);
+        chunk.write(OpCode::Nil as ByteCode, 3);
+        assert_eq!(all_lines(&chunk), [1, 1, 2, 2, 2, 3]);
+        assert_eq!(chunk.lines.len(), 3);
+    }
+
+    #[test]
+    fn compares_compiled_code() {
+        let mut interner = StringInterner::default();
+        let mut compile = |source| compile(source, &mut interner).unwrap();
+        let folded = compile("print 1 + 2;\nfun f(a) { return a; }");
+        let reformatted = compile("print 1 +\n  2; fun f(a) {\n  return a;\n}");
+        let prefolded = compile("print 3; fun f(a) { return a; }");
+        let different = compile("print 1 + 3;\nfun f(a) { return a; }");
+
+        assert!(folded.bytecode_eq(&interner, &reformatted, &interner));
+        assert!(folded.bytecode_eq(&interner, &prefolded, &interner));
+        assert!(!folded.bytecode_eq(&interner, &different, &interner));
+        assert_eq!(
+            folded.diff(&interner, &different, &interner),
+            concat!(
+                "- Constant 0 3\n",
+                "+ Constant 0 4\n",
+                "  Print\n",
+                "  Constant 2 \"<fn f>\"\n",
+                "  DefineGlobal 1 \"f\"\n",
+                "  Return\n",
+            )
+        );
+    }
+
+    #[test]
+    fn decodes_instructions() {
+        let mut interner = StringInterner::default();
+        let mut chunk = compile("var a = 1;", &mut interner).unwrap();
+        chunk.write(OpCode::Constant as ByteCode, 2);
+        let instructions: Vec<_> = chunk.instructions().collect();
+
+        let offsets: Vec<_> = instructions.iter().map(|instr| instr.offset).collect();
+        assert_eq!(offsets, vec![0, 2, 4, 5]);
+        assert!(matches!(instructions[1].opcode, Ok(OpCode::DefineGlobal)));
+        assert_eq!(instructions[1].operands, &[0]);
+        assert!(matches!(
+            instructions[1].constant,
+            Some(Value::InternedString(_))
+        ));
+        // The trailing `Constant` is missing its operand.
+        assert!(instructions[3].operands.is_empty());
+        assert!(instructions[3].constant.is_none());
+    }
+
+    #[test]
+    fn compares_chunks_from_different_interners() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("var a = \"x\"; fun f() { print a; }", &mut interner).unwrap();
+        let mut other_interner = StringInterner::default();
+        other_interner.get_or_intern("unrelated");
+        let source = "var a = \"x\";\nfun f() {\n  print a;\n}";
+        let same = compile(source, &mut other_interner).unwrap();
+        let renamed = compile("var a = \"y\"; fun f() { print a; }", &mut other_interner).unwrap();
+
+        assert!(chunk.bytecode_eq(&interner, &same, &other_interner));
+        assert!(!chunk.bytecode_eq(&interner, &renamed, &other_interner));
+    }
+
+    #[test]
+    fn diff_aligns_around_inserted_instructions() {
+        let mut interner = StringInterner::default();
+        let before = compile("print 1; print 2;", &mut interner).unwrap();
+        let after = compile("print 1; print nil; print 2;", &mut interner).unwrap();
+
+        assert_eq!(
+            before.diff(&interner, &after, &interner),
+            concat!(
+                "  Constant 0 1\n",
+                "  Print\n",
+                "+ Nil\n",
+                "+ Print\n",
+                "  Constant 1 2\n",
+                "  Print\n",
+                "  Return\n",
+            )
+        );
+    }
+}
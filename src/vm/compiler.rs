@@ -1,27 +1,168 @@
-use string_interner::StringInterner;
+use std::rc::Rc;
 
-use crate::syntax::{parser::Parser, token::TokenType};
+use string_interner::{DefaultSymbol, StringInterner};
 
-use super::{bytecode::Chunk, disassembler::disassemble_chunk};
+use crate::syntax::{
+    parser::{Parser, ParserError, ParserWarning},
+    token::TokenType,
+};
 
-pub fn compile(text: &str, strings: &mut StringInterner) -> Result<Chunk, ()> {
+use super::{
+    bytecode::{ByteCode, Chunk, OpCode},
+    disassembler::disassemble_chunk,
+    value::Function,
+    verifier::verify_chunk,
+    vm::{Vm, VmError},
+};
+
+static PRINT_CODE: bool = false;
+
+/// Compiles `text` into a new chunk. Any warnings are dropped; use
+/// `compile_into` to get them.
+pub fn compile(text: &str, strings: &mut StringInterner) -> Result<Chunk, Vec<ParserError>> {
+    let mut chunk = Chunk::default();
+    compile_into(text, &mut chunk, strings)?;
+    Ok(chunk)
+}
+
+/// A compiled chunk together with the string table it refers to.
+///
+/// Unlike `compile`, which shares the caller's interner with the VM, a
+/// program owns its strings: each run interns runtime strings (e.g. from
+/// concatenation) into a fresh copy, so the program can be run any number of
+/// times with the same result. The chunk itself is never modified by running
+/// it, so every run shares it.
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    script: Rc<Function>,
+    interner: StringInterner,
+    warnings: Vec<ParserWarning>,
+}
+
+impl CompiledProgram {
+    pub fn chunk(&self) -> &Chunk {
+        self.script.chunk()
+    }
+
+    pub fn interner(&self) -> &StringInterner {
+        &self.interner
+    }
+
+    /// Returns the warnings found while compiling, for the caller to report.
+    pub fn warnings(&self) -> &[ParserWarning] {
+        &self.warnings
+    }
+
+    /// Verifies the chunk against the program's strings before running it.
+    pub fn run(&self, vm: &mut Vm) -> Result<(), VmError> {
+        verify_chunk(self.chunk(), &self.interner).map_err(VmError::InvalidChunk)?;
+        let mut runtime_strings = self.interner.clone();
+        vm.run_script(Rc::clone(&self.script), &mut runtime_strings)
+    }
+}
+
+pub fn compile_program(text: &str) -> Result<CompiledProgram, Vec<ParserError>> {
+    let mut interner = StringInterner::default();
     let mut chunk = Chunk::default();
+    let warnings = compile_into(text, &mut chunk, &mut interner)?;
+    Ok(CompiledProgram {
+        script: Rc::new(Function::script(chunk)),
+        interner,
+        warnings,
+    })
+}
+
+/// Appends the code for `text` to `chunk`, which must be empty or have been
+/// produced by a previous `compile`/`compile_into`. The previous trailing
+/// `Return` is dropped so the combined chunk runs both snippets.
+///
+/// Returns any warnings, for the caller to report. On failure, returns every
+/// error found, in source order. The chunk then holds partial code and must
+/// not be run.
+pub fn compile_into(
+    text: &str,
+    chunk: &mut Chunk,
+    strings: &mut StringInterner,
+) -> Result<Vec<ParserWarning>, Vec<ParserError>> {
+    compile_into_with_natives(text, chunk, strings, &[])
+}
+
+/// Like `compile_into`, but calls to the natives in `natives`, a VM's
+/// `native_registry`, are compiled to `OpCode::CallNative`. The chunk must
+/// only be run on that VM.
+pub fn compile_into_with_natives(
+    text: &str,
+    chunk: &mut Chunk,
+    strings: &mut StringInterner,
+    natives: &[Option<DefaultSymbol>],
+) -> Result<Vec<ParserWarning>, Vec<ParserError>> {
+    let len = chunk.len();
+    if len > 0 && chunk[len - 1] == OpCode::Return as ByteCode {
+        chunk.truncate(len - 1);
+    }
+    let mut errors = Vec::new();
+    let warnings;
     {
-        let mut parser = Parser::new(text, &mut chunk, strings);
-        if let Err(err) = parser.advance() {
-            eprintln!("{}", err);
+        let mut parser = Parser::new(text, chunk, strings);
+        parser.set_natives(natives);
+        // Skip past bad leading tokens so they aren't reported twice.
+        while let Err(err) = parser.advance() {
+            errors.push(err);
         }
         while !parser.is_done() {
-            let result = parser.declaration();
-            if let Err(err) = result {
-                eprintln!("{}", err);
+            if let Err(err) = parser.declaration() {
+                errors.push(err);
             }
         }
         parser.end();
+        warnings = parser.take_warnings();
         if let Err(err) = parser.consume(TokenType::Eof, "Expected Eof") {
-            eprintln!("{}", err);
+            errors.push(err);
         }
     }
-    println!("{}", disassemble_chunk(&chunk, "code"));
-    Ok(chunk)
+    if PRINT_CODE {
+        println!("{}", disassemble_chunk(chunk, "code", strings));
+    }
+    if errors.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs_are_verified_before_running() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("print \"a\";", &mut interner).unwrap();
+        let program = CompiledProgram {
+            script: Rc::new(Function::script(chunk)),
+            interner: StringInterner::default(),
+            warnings: Vec::new(),
+        };
+        let mut vm = Vm::default();
+
+        let err = program.run(&mut vm).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "String constant 0 in script is not in the interner"
+        );
+    }
+
+    #[test]
+    fn returns_warnings_to_the_caller() {
+        let program = compile_program("var a; if (a = 1) print a;").unwrap();
+        assert_eq!(
+            program.warnings(),
+            [ParserWarning::AssignmentInCondition(1)]
+        );
+
+        let mut chunk = Chunk::default();
+        let mut interner = StringInterner::default();
+        let warnings = compile_into("var b;\nwhile (b = nil) {}", &mut chunk, &mut interner);
+        assert_eq!(warnings.unwrap(), [ParserWarning::AssignmentInCondition(2)]);
+    }
 }
@@ -1,13 +1,22 @@
 use std::convert::TryFrom;
 
+use string_interner::StringInterner;
+
 use super::bytecode::{ByteCode, Chunk, Offset, OpCode};
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) -> String {
+pub fn disassemble_chunk(chunk: &Chunk, name: &str, interner: &StringInterner) -> String {
     let mut result = format!("== {} ==\n", name);
     let len = chunk.len();
     let mut index: usize = 0;
     while index < len {
-        index = disassemble_instruction(chunk, chunk[index], index, &mut result);
+        index = disassemble_instruction(chunk, chunk[index], index, interner, &mut result);
+    }
+    if !chunk.constants().is_empty() {
+        result.push_str(&format!(
+            "// {} constants, {} deduplicated\n",
+            chunk.constants().len(),
+            chunk.deduplicated_constants()
+        ));
     }
     result
 }
@@ -16,6 +25,7 @@ pub fn disassemble_instruction(
     chunk: &Chunk,
     instr: ByteCode,
     offset: usize,
+    interner: &StringInterner,
     output: &mut String,
 ) -> usize {
     output.push_str(format!("{:04} ", offset).as_str());
@@ -29,21 +39,89 @@ pub fn disassemble_instruction(
     if let Ok(opcode) = OpCode::try_from(instr) {
         match opcode {
             OpCode::Constant => {
-                output.push_str(constant_instruction("Constant", chunk, offset).as_str());
+                output.push_str(constant_instruction("Constant", chunk, offset, interner).as_str());
+                return offset + 2;
+            }
+            OpCode::ConstantLong => {
+                output.push_str(
+                    constant_instruction("ConstantLong", chunk, offset, interner).as_str(),
+                );
+                return offset + 4;
+            }
+            OpCode::GetLocal => {
+                output.push_str(byte_instruction("GetLocal", chunk, offset).as_str());
+                return offset + 2;
+            }
+            OpCode::SetLocal => {
+                output.push_str(byte_instruction("SetLocal", chunk, offset).as_str());
                 return offset + 2;
             }
             OpCode::GetGlobal => {
-                output.push_str(constant_instruction("GetGlobal", chunk, offset).as_str());
+                output
+                    .push_str(constant_instruction("GetGlobal", chunk, offset, interner).as_str());
                 return offset + 2;
             }
+            OpCode::GetGlobalLong => {
+                output.push_str(
+                    constant_instruction("GetGlobalLong", chunk, offset, interner).as_str(),
+                );
+                return offset + 4;
+            }
             OpCode::DefineGlobal => {
-                output.push_str(constant_instruction("DefineGlobal", chunk, offset).as_str());
+                output.push_str(
+                    constant_instruction("DefineGlobal", chunk, offset, interner).as_str(),
+                );
                 return offset + 2;
             }
+            OpCode::DefineGlobalLong => {
+                output.push_str(
+                    constant_instruction("DefineGlobalLong", chunk, offset, interner).as_str(),
+                );
+                return offset + 4;
+            }
             OpCode::SetGlobal => {
-                output.push_str(constant_instruction("SetGlobal", chunk, offset).as_str());
+                output
+                    .push_str(constant_instruction("SetGlobal", chunk, offset, interner).as_str());
                 return offset + 2;
             }
+            OpCode::SetGlobalLong => {
+                output.push_str(
+                    constant_instruction("SetGlobalLong", chunk, offset, interner).as_str(),
+                );
+                return offset + 4;
+            }
+            OpCode::GetProperty => {
+                output.push_str(
+                    constant_instruction("GetProperty", chunk, offset, interner).as_str(),
+                );
+                return offset + 2;
+            }
+            OpCode::GetPropertyLong => {
+                output.push_str(
+                    constant_instruction("GetPropertyLong", chunk, offset, interner).as_str(),
+                );
+                return offset + 4;
+            }
+            OpCode::Call => {
+                output.push_str(byte_instruction("Call", chunk, offset).as_str());
+                return offset + 2;
+            }
+            OpCode::CallNative => {
+                output.push_str(native_call_instruction(chunk, offset).as_str());
+                return offset + 3;
+            }
+            OpCode::Jump => {
+                output.push_str(jump_instruction("Jump", 1, chunk, offset).as_str());
+                return offset + 3;
+            }
+            OpCode::JumpIfFalse => {
+                output.push_str(jump_instruction("JumpIfFalse", 1, chunk, offset).as_str());
+                return offset + 3;
+            }
+            OpCode::Loop => {
+                output.push_str(jump_instruction("Loop", -1, chunk, offset).as_str());
+                return offset + 3;
+            }
             val => {
                 output.push_str(format!("{:?}\n", val).as_str());
                 return offset + 1;
@@ -54,11 +132,54 @@ pub fn disassemble_instruction(
         return offset + 1;
     }
 
-    fn constant_instruction(name: &str, chunk: &Chunk, offset: Offset) -> String {
-        if let Some(constant_idx) = chunk.get_bytecode(offset + 1) {
-            match chunk.get_constant(*constant_idx) {
+    fn byte_instruction(name: &str, chunk: &Chunk, offset: Offset) -> String {
+        match chunk.get_bytecode(offset + 1) {
+            Some(slot) => format!("{:<16} {:4}\n", name, slot),
+            None => format!("{} <invalid bytecode offset {}>\n", name, offset + 1),
+        }
+    }
+
+    fn native_call_instruction(chunk: &Chunk, offset: Offset) -> String {
+        match (
+            chunk.get_bytecode(offset + 1),
+            chunk.get_bytecode(offset + 2),
+        ) {
+            (Some(native), Some(arg_count)) => {
+                format!("{:<16} {:4} ({} args)\n", "CallNative", native, arg_count)
+            }
+            _ => format!("CallNative <invalid bytecode offset {}>\n", offset + 1),
+        }
+    }
+
+    fn jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: Offset) -> String {
+        match (
+            chunk.get_bytecode(offset + 1),
+            chunk.get_bytecode(offset + 2),
+        ) {
+            (Some(high), Some(low)) => {
+                let jump = ((*high as isize) << 8 | *low as isize) * sign;
+                let target = offset as isize + 3 + jump;
+                format!("{:<16} {:4} -> {}\n", name, offset, target)
+            }
+            _ => format!("{} <invalid bytecode offset {}>\n", name, offset + 1),
+        }
+    }
+
+    fn constant_instruction(
+        name: &str,
+        chunk: &Chunk,
+        offset: Offset,
+        interner: &StringInterner,
+    ) -> String {
+        if let Some(constant_idx) = chunk.constant_index(offset) {
+            match chunk.get_constant(constant_idx) {
                 Some(val) => {
-                    format!("{:<16} {:4} {:?}\n", name, constant_idx, val)
+                    format!(
+                        "{:<16} {:4} {}\n",
+                        name,
+                        constant_idx,
+                        val.to_string(interner)
+                    )
                 }
                 None => {
                     format!("{} <invalid constant offset {}>\n", name, constant_idx)
@@ -69,3 +190,132 @@ pub fn disassemble_instruction(
         }
     }
 }
+
+/// Describes each instruction as its opcode, operand bytes and any constant,
+/// leaving out offsets and lines so chunks compiled from different sources
+/// line up.
+pub(crate) fn instruction_texts(chunk: &Chunk, interner: &StringInterner) -> Vec<String> {
+    chunk
+        .instructions()
+        .map(|instr| {
+            let mut text = match instr.opcode {
+                Ok(opcode) => format!("{:?}", opcode),
+                Err(byte) => return format!("<unknown opcode {}>", byte),
+            };
+            for byte in instr.operands {
+                text.push_str(&format!(" {}", byte));
+            }
+            if let Some(constant) = instr.constant {
+                text.push_str(&format!(" {}", constant.to_json(interner)));
+            }
+            text
+        })
+        .collect()
+}
+
+/// Exports `chunk` as JSON for external tools: the constant table plus one
+/// object per instruction with its offset, line, opcode name, operand bytes
+/// and, for constant instructions, the resolved constant.
+pub fn chunk_to_json(chunk: &Chunk, interner: &StringInterner) -> String {
+    let constants: Vec<String> = chunk
+        .constants()
+        .iter()
+        .map(|value| value.to_json(interner))
+        .collect();
+
+    let instructions: Vec<String> = chunk
+        .instructions()
+        .map(|instr| {
+            let line = chunk.get_line(instr.offset);
+            let opcode = match instr.opcode {
+                Ok(opcode) => format!("\"{:?}\"", opcode),
+                Err(byte) => {
+                    return format!(
+                        "{{\"offset\":{},\"line\":{},\"opcode\":null,\"operands\":[{}]}}",
+                        instr.offset, line, byte
+                    )
+                }
+            };
+            let operands: Vec<String> =
+                instr.operands.iter().map(|byte| byte.to_string()).collect();
+            let mut json = format!(
+                "{{\"offset\":{},\"line\":{},\"opcode\":{},\"operands\":[{}]",
+                instr.offset,
+                line,
+                opcode,
+                operands.join(",")
+            );
+            if let Some(constant) = instr.constant {
+                json.push_str(&format!(",\"constant\":{}", constant.to_json(interner)));
+            }
+            json.push('}');
+            json
+        })
+        .collect();
+
+    format!(
+        "{{\"constants\":[{}],\"code\":[{}]}}",
+        constants.join(","),
+        instructions.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{compiler::compile, value::Value};
+
+    #[test]
+    fn exports_chunk_as_json() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("var a = 1;\nprint a;", &mut interner).unwrap();
+
+        let expected = concat!(
+            r#"{"constants":["a",1],"code":["#,
+            r#"{"offset":0,"line":1,"opcode":"Constant","operands":[1],"constant":1},"#,
+            r#"{"offset":2,"line":1,"opcode":"DefineGlobal","operands":[0],"constant":"a"},"#,
+            r#"{"offset":4,"line":2,"opcode":"GetGlobal","operands":[0],"constant":"a"},"#,
+            r#"{"offset":6,"line":2,"opcode":"Print","operands":[]},"#,
+            r#"{"offset":7,"line":2,"opcode":"Return","operands":[]}"#,
+            "]}"
+        );
+        assert_eq!(chunk_to_json(&chunk, &interner), expected);
+    }
+
+    #[test]
+    fn disassembles_long_constants() {
+        let mut interner = StringInterner::default();
+        let source: String = (0..257).map(|i| format!("print {};", i)).collect();
+        let chunk = compile(&source, &mut interner).unwrap();
+
+        let disassembly = disassemble_chunk(&chunk, "code", &interner);
+        assert!(disassembly.contains("ConstantLong      256 256\n"));
+        assert!(chunk_to_json(&chunk, &interner).contains(
+            r#"{"offset":768,"line":1,"opcode":"ConstantLong","operands":[0,1,0],"constant":256}"#
+        ));
+    }
+
+    #[test]
+    fn disassembles_negative_numbers_like_print() {
+        let interner = StringInterner::default();
+        let mut chunk = Chunk::default();
+        let idx = chunk.add_value(Value::Number(-5.0)).unwrap();
+        chunk.write(OpCode::Constant as ByteCode, 1);
+        chunk.write(idx as ByteCode, 1);
+
+        assert_eq!(
+            disassemble_chunk(&chunk, "code", &interner),
+            "== code ==\n0000    1 Constant            0 -5\n// 1 constants, 0 deduplicated\n"
+        );
+    }
+
+    #[test]
+    fn reports_deduplicated_constants() {
+        let mut interner = StringInterner::default();
+        let source = "print 1; print 1; print \"a\"; print \"a\"; print \"a\"; print 2;";
+        let chunk = compile(source, &mut interner).unwrap();
+
+        let disassembly = disassemble_chunk(&chunk, "code", &interner);
+        assert!(disassembly.ends_with("// 3 constants, 3 deduplicated\n"));
+    }
+}
@@ -1,5 +1,8 @@
 pub mod bytecode;
 pub mod compiler;
 pub mod disassembler;
+pub mod natives;
 pub mod value;
+pub mod verifier;
+#[allow(clippy::module_inception)]
 pub mod vm;
@@ -0,0 +1,79 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use string_interner::StringInterner;
+
+use super::{
+    value::Value,
+    vm::{Vm, VmError},
+};
+
+/// Defines the natives every program can use.
+pub fn define_builtins(vm: &mut Vm, interner: &mut StringInterner) {
+    vm.define_native("clock", clock, interner);
+    vm.define_native("is_int", is_int, interner);
+    vm.define_native("to_number", to_number, interner);
+    vm.define_native("str", str, interner);
+    vm.define_native("mod_floor", mod_floor, interner);
+}
+
+/// Returns the number of seconds since the Unix epoch.
+pub fn clock(_args: &[Value], _interner: &mut StringInterner) -> Result<Value, VmError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| VmError::RuntimeError)?;
+    Ok(Value::Number(now.as_secs_f64()))
+}
+
+/// Returns whether its argument is a whole number. Non-numbers are not.
+pub fn is_int(args: &[Value], _interner: &mut StringInterner) -> Result<Value, VmError> {
+    match args {
+        [value] => Ok(Value::Bool(value.is_integer())),
+        _ => Err(VmError::WrongArity(1, args.len())),
+    }
+}
+
+/// Parses a string, ignoring surrounding whitespace, into a number. Returns
+/// `nil` if it isn't one so scripts can check. Numbers are returned as is.
+pub fn to_number(args: &[Value], interner: &mut StringInterner) -> Result<Value, VmError> {
+    let text = match args {
+        [Value::Number(num)] => return Ok(Value::Number(*num)),
+        [Value::String(str)] => str.as_str(),
+        [Value::InternedString(symbol)] => {
+            interner.resolve(*symbol).ok_or(VmError::RuntimeError)?
+        }
+        [value] => {
+            return Err(VmError::TypeError(format!(
+                "to_number requires a string or number, got {}",
+                value.type_name()
+            )))
+        }
+        _ => return Err(VmError::WrongArity(1, args.len())),
+    };
+    Ok(text.trim().parse().map_or(Value::Nil, Value::Number))
+}
+
+/// Returns the text `print` would write for its argument, as a string.
+pub fn str(args: &[Value], interner: &mut StringInterner) -> Result<Value, VmError> {
+    match args {
+        [value] => {
+            let text = value.to_string(interner);
+            Ok(Value::InternedString(interner.get_or_intern(text)))
+        }
+        _ => Err(VmError::WrongArity(1, args.len())),
+    }
+}
+
+/// Returns `a` modulo `b` using floored division, so the result takes the
+/// sign of `b`: `mod_floor(-1, 3)` is 2 where the remainder would be -1.
+pub fn mod_floor(args: &[Value], _interner: &mut StringInterner) -> Result<Value, VmError> {
+    match args {
+        [Value::Number(_), Value::Number(b)] if *b == 0.0 => Err(VmError::DivisionByZero),
+        [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a - b * (a / b).floor())),
+        [a, b] => Err(VmError::TypeError(format!(
+            "mod_floor requires two numbers, got {} and {}",
+            a.type_name(),
+            b.type_name()
+        ))),
+        _ => Err(VmError::WrongArity(2, args.len())),
+    }
+}
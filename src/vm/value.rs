@@ -1,6 +1,13 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    io::{self, Write},
+    rc::Rc,
+};
+
 use string_interner::{DefaultSymbol, StringInterner};
 
-use super::vm::VmError;
+use super::{bytecode::Chunk, vm::VmError};
 
 #[derive(Debug, Clone)]
 pub enum Value {
@@ -9,38 +16,144 @@ pub enum Value {
     Number(f64),
     String(String),
     InternedString(DefaultSymbol),
+    Char(char),
+    Function(Rc<Function>),
+    NativeFn(NativeFn),
+}
+
+/// A function implemented by the host, called with its arguments and the
+/// interner their strings belong to.
+pub type NativeFn = fn(&[Value], &mut StringInterner) -> Result<Value, VmError>;
+
+/// A compiled function. The top-level script is a function without a name.
+pub struct Function {
+    pub(crate) arity: usize,
+    pub(crate) chunk: Chunk,
+    pub(crate) name: Option<DefaultSymbol>,
+}
+
+impl Function {
+    pub fn script(chunk: Chunk) -> Function {
+        Function {
+            arity: 0,
+            chunk,
+            name: None,
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn name(&self) -> Option<DefaultSymbol> {
+        self.name
+    }
+}
+
+// The chunk is left out as it would swamp stack traces and disassembly.
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
 }
 
 impl Value {
-    pub fn is_falsey(&self, interner: &StringInterner) -> bool {
+    // Pre-built singletons for the literal opcodes. Being `const`, they are
+    // constructed at compile time and never allocate, whatever heap variants
+    // `Value` grows later.
+    pub const NIL: Value = Value::Nil;
+    pub const TRUE: Value = Value::Bool(true);
+    pub const FALSE: Value = Value::Bool(false);
+
+    /// True for finite numbers without a fractional part. Other values are
+    /// never integers.
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Value::Number(val) => val.is_finite() && val.fract() == 0.0,
+            _ => false,
+        }
+    }
+
+    /// Only `nil` and `false` are falsey, as in reference Lox. In particular
+    /// the empty string is truthy.
+    pub fn is_falsey(&self) -> bool {
         use Value::*;
         match self {
             Nil => true,
             Bool(val) => !val,
-            String(val) => return val.len() == 0,
-            InternedString(val) => match interner.resolve(*val) {
-                None => true,
-                Some(str) => return str.len() == 0,
-            },
             _ => false,
         }
     }
 
     pub fn to_string(&self, interner: &StringInterner) -> String {
+        let mut bytes = Vec::new();
+        // Writing into a `Vec` can't fail and only ever writes UTF-8.
+        let _ = self.write_to(&mut bytes, interner);
+        std::string::String::from_utf8(bytes).unwrap_or_default()
+    }
+
+    /// Writes the printed form of the value without building a `String`.
+    pub fn write_to(&self, out: &mut impl Write, interner: &StringInterner) -> io::Result<()> {
         use Value::*;
         match self {
-            Nil => "nil".to_string(),
-            Bool(val) => if *val { "true" } else { "false" }.to_string(),
-            Number(val) => val.to_string(),
-            String(val) => return val.to_string(),
+            Nil => out.write_all(b"nil"),
+            Bool(val) => out.write_all(if *val { b"true" } else { b"false" }),
+            Number(val) => write_number(out, *val),
+            String(val) => out.write_all(val.as_bytes()),
             InternedString(val) => match interner.resolve(*val) {
-                None => "<invalid interned string>",
-                Some(str) => str,
+                None => out.write_all(b"<invalid interned string>"),
+                Some(str) => out.write_all(str.as_bytes()),
+            },
+            Char(val) => write!(out, "{}", val),
+            Function(function) => match function.name.and_then(|name| interner.resolve(name)) {
+                Some("") => out.write_all(b"<fn>"),
+                Some(name) => write!(out, "<fn {}>", name),
+                None => out.write_all(b"<script>"),
+            },
+            NativeFn(_) => out.write_all(b"<native fn>"),
+        }
+    }
+
+    /// Formats the value as JSON. Non-finite numbers have no JSON
+    /// representation and become `null`.
+    pub fn to_json(&self, interner: &StringInterner) -> String {
+        use Value::*;
+        match self {
+            Nil => "null".to_string(),
+            Bool(_) => self.to_string(interner),
+            Number(val) if val.is_finite() => self.to_string(interner),
+            Number(_) => "null".to_string(),
+            String(_) | InternedString(_) | Char(_) | Function(_) | NativeFn(_) => {
+                let str = self.to_string(interner);
+                let mut json = std::string::String::with_capacity(str.len() + 2);
+                json.push('"');
+                for char in str.chars() {
+                    match char {
+                        '"' => json.push_str("\\\""),
+                        '\\' => json.push_str("\\\\"),
+                        '\n' => json.push_str("\\n"),
+                        '\r' => json.push_str("\\r"),
+                        '\t' => json.push_str("\\t"),
+                        c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => json.push(c),
+                    }
+                }
+                json.push('"');
+                json
             }
-            .to_string(),
         }
     }
 
+    /// The same interned string or the same function is equal without
+    /// looking any further. There's no blanket identity fast path since `NaN`
+    /// must not equal itself.
     pub fn equal(&self, other: &Value) -> bool {
         use Value::*;
         match (self, other) {
@@ -48,27 +161,81 @@ impl Value {
             (Bool(a), Bool(b)) => a == b,
             (Number(a), Number(b)) => a == b,
             (String(a), String(b)) => a == b,
+            // Interning keeps one symbol per string, so equal symbols mean
+            // equal contents.
             (InternedString(a), InternedString(b)) => a == b,
+            (Char(a), Char(b)) => a == b,
+            // Functions are only ever equal to themselves.
+            (Function(a), Function(b)) => Rc::ptr_eq(a, b),
+            (NativeFn(a), NativeFn(b)) => std::ptr::fn_addr_eq(*a, *b),
             _ => false,
         }
     }
 
-    pub fn greater(&self, other: &Value) -> Result<bool, VmError> {
+    /// Orders two numbers, two strings or two chars. Returns `None` for any other pair,
+    /// and for `NaN`, so callers such as sorting can reject them.
+    pub fn partial_cmp(&self, other: &Value, interner: &StringInterner) -> Option<Ordering> {
+        use Value::*;
+        match (self, other) {
+            (Number(a), Number(b)) => a.partial_cmp(b),
+            (String(a), String(b)) => Some(a.cmp(b)),
+            (InternedString(a), InternedString(b)) if a == b => Some(Ordering::Equal),
+            (InternedString(a), InternedString(b)) => {
+                Some(interner.resolve(*a)?.cmp(interner.resolve(*b)?))
+            }
+            (String(a), InternedString(b)) => Some(a.as_str().cmp(interner.resolve(*b)?)),
+            (InternedString(a), String(b)) => Some(interner.resolve(*a)?.cmp(b.as_str())),
+            (Char(a), Char(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    /// The name of the value's type, as used in error messages.
+    pub fn type_name(&self) -> &'static str {
+        use Value::*;
+        match self {
+            Nil => "nil",
+            Bool(_) => "bool",
+            Number(_) => "number",
+            String(_) | InternedString(_) => "string",
+            Char(_) => "char",
+            Function(_) | NativeFn(_) => "function",
+        }
+    }
+
+    fn binary_type_error(&self, other: &Value, expected: &str) -> VmError {
+        VmError::TypeError(format!(
+            "Operands must be {}, got {} and {}",
+            expected,
+            self.type_name(),
+            other.type_name()
+        ))
+    }
+
+    pub fn greater(&self, other: &Value, interner: &StringInterner) -> Result<bool, VmError> {
         use Value::*;
         match (self, other) {
             (Number(a), Number(b)) => Ok(a > b),
-            _ => Err(VmError::TypeError("> requires two numbers".to_string())),
+            _ => Ok(self.ordering(other, interner)? == Ordering::Greater),
         }
     }
 
-    pub fn less(&self, other: &Value) -> Result<bool, VmError> {
+    pub fn less(&self, other: &Value, interner: &StringInterner) -> Result<bool, VmError> {
         use Value::*;
         match (self, other) {
             (Number(a), Number(b)) => Ok(a < b),
-            _ => Err(VmError::TypeError("< requires two numbers".to_string())),
+            _ => Ok(self.ordering(other, interner)? == Ordering::Less),
         }
     }
 
+    /// Orders the non-number operands of `<` and `>`. Strings are compared
+    /// as resolved slices, so neither side is copied whichever variant holds
+    /// it.
+    fn ordering(&self, other: &Value, interner: &StringInterner) -> Result<Ordering, VmError> {
+        self.partial_cmp(other, interner)
+            .ok_or_else(|| self.binary_type_error(other, "two numbers, two strings or two chars"))
+    }
+
     pub fn add(&self, other: &Value, interner: &mut StringInterner) -> Result<Value, VmError> {
         use Value::*;
         match (self, other) {
@@ -83,9 +250,7 @@ impl Value {
                     _ => Err(VmError::RuntimeError),
                 }
             }
-            _ => Err(VmError::TypeError(
-                "+ requires two numbers or strings".to_string(),
-            )),
+            _ => Err(self.binary_type_error(other, "two numbers or two strings")),
         }
     }
 
@@ -93,7 +258,7 @@ impl Value {
         use Value::*;
         match (self, other) {
             (Number(a), Number(b)) => Ok(Number(a - b)),
-            _ => Err(VmError::TypeError("- requires two numbers".to_string())),
+            _ => Err(self.binary_type_error(other, "numbers")),
         }
     }
 
@@ -101,7 +266,7 @@ impl Value {
         use Value::*;
         match (self, other) {
             (Number(a), Number(b)) => Ok(Number(a * b)),
-            _ => Err(VmError::TypeError("* requires two numbers".to_string())),
+            _ => Err(self.binary_type_error(other, "numbers")),
         }
     }
 
@@ -109,7 +274,7 @@ impl Value {
         use Value::*;
         match (self, other) {
             (Number(a), Number(b)) => Ok(Number(a / b)),
-            _ => Err(VmError::TypeError("/ requires two numbers".to_string())),
+            _ => Err(self.binary_type_error(other, "numbers")),
         }
     }
 
@@ -117,7 +282,205 @@ impl Value {
         use Value::*;
         match self {
             Number(number) => Ok(Number(-number)),
-            _ => Err(VmError::TypeError("- requires one number".to_string())),
+            _ => Err(VmError::TypeError(format!(
+                "Operand must be a number, got {}",
+                self.type_name()
+            ))),
+        }
+    }
+}
+
+/// Integral numbers print without a trailing `.0`, as in reference Lox.
+/// Magnitudes outside `1e-6..1e21` switch to exponent form rather than
+/// spelling out hundreds of digits; the thresholds match JavaScript's.
+fn write_number(out: &mut impl Write, val: f64) -> io::Result<()> {
+    let abs = val.abs();
+    if abs == 0.0 || !abs.is_finite() || (1e-6..1e21).contains(&abs) {
+        write!(out, "{}", val)
+    } else {
+        write!(out, "{:e}", val)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interned_strings_compare_by_symbol() {
+        let mut interner = StringInterner::default();
+        let long = "a".repeat(4096);
+        let a = Value::InternedString(interner.get_or_intern(&long));
+        let b = Value::InternedString(interner.get_or_intern(&long));
+        let c = Value::InternedString(interner.get_or_intern("b"));
+
+        assert!(a.equal(&a));
+        assert!(a.equal(&b));
+        assert!(!a.equal(&c));
+    }
+
+    #[test]
+    fn nan_is_not_equal_to_itself() {
+        let nan = Value::Number(f64::NAN);
+        assert!(!nan.equal(&nan));
+    }
+
+    #[test]
+    fn only_nil_and_false_are_falsey() {
+        let mut interner = StringInterner::default();
+        let empty = Value::InternedString(interner.get_or_intern(""));
+
+        assert!(Value::Nil.is_falsey());
+        assert!(Value::Bool(false).is_falsey());
+        assert!(!Value::Bool(true).is_falsey());
+        assert!(!Value::Number(0.0).is_falsey());
+        assert!(!empty.is_falsey());
+        assert!(!Value::String(String::new()).is_falsey());
+    }
+
+    #[test]
+    fn formats_values_as_json() {
+        let mut interner = StringInterner::default();
+        let str = Value::InternedString(interner.get_or_intern("a \"quoted\"\\\n\u{1}"));
+
+        assert_eq!(Value::Nil.to_json(&interner), "null");
+        assert_eq!(Value::Bool(true).to_json(&interner), "true");
+        assert_eq!(Value::Number(1.5).to_json(&interner), "1.5");
+        assert_eq!(Value::Number(f64::NAN).to_json(&interner), "null");
+        assert_eq!(str.to_json(&interner), "\"a \\\"quoted\\\"\\\\\\n\\u0001\"");
+    }
+
+    #[test]
+    fn writes_printed_form_directly() {
+        let mut interner = StringInterner::default();
+        let values = [
+            Value::Nil,
+            Value::Bool(false),
+            Value::Number(2.5),
+            Value::String("owned".to_string()),
+            Value::InternedString(interner.get_or_intern("interned")),
+        ];
+
+        let mut out = Vec::new();
+        for value in values.iter() {
+            value.write_to(&mut out, &interner).unwrap();
+            out.push(b' ');
+        }
+        assert_eq!(out, b"nil false 2.5 owned interned ");
+        assert_eq!(values[4].to_string(&interner), "interned");
+    }
+
+    #[test]
+    fn orders_comparable_values() {
+        let mut interner = StringInterner::default();
+        let apple = Value::InternedString(interner.get_or_intern("apple"));
+        let pear = Value::InternedString(interner.get_or_intern("pear"));
+
+        let cmp = |a: &Value, b: &Value| a.partial_cmp(b, &interner);
+        assert_eq!(
+            cmp(&Value::Number(1.0), &Value::Number(2.0)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            cmp(&Value::Number(2.0), &Value::Number(2.0)),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(cmp(&pear, &apple), Some(Ordering::Greater));
+        assert_eq!(
+            cmp(&Value::String("apple".to_string()), &pear),
+            Some(Ordering::Less)
+        );
+        assert_eq!(cmp(&Value::Number(f64::NAN), &Value::Number(1.0)), None);
+        assert_eq!(cmp(&Value::Number(1.0), &apple), None);
+        assert_eq!(cmp(&Value::Nil, &Value::Nil), None);
+    }
+
+    #[test]
+    fn type_errors_name_the_operand_types() {
+        let mut interner = StringInterner::default();
+        let x = Value::InternedString(interner.get_or_intern("x"));
+
+        match x.negate() {
+            Err(err) => assert_eq!(err.to_string(), "Operand must be a number, got string"),
+            Ok(_) => panic!("Expected a type error"),
         }
+        match Value::Number(1.0).add(&Value::Nil, &mut interner) {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "Operands must be two numbers or two strings, got number and nil"
+            ),
+            Ok(_) => panic!("Expected a type error"),
+        }
+        match Value::Bool(true).less(&Value::Number(1.0), &interner) {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "Operands must be two numbers, two strings or two chars, got bool and number"
+            ),
+            Ok(_) => panic!("Expected a type error"),
+        }
+    }
+
+    #[test]
+    fn chars_compare_and_print() {
+        let interner = StringInterner::default();
+        let a = Value::Char('a');
+        assert!(a.equal(&Value::Char('a')));
+        assert!(!a.equal(&Value::Char('b')));
+        assert_eq!(
+            a.partial_cmp(&Value::Char('b'), &interner),
+            Some(Ordering::Less)
+        );
+        assert_eq!(a.to_string(&interner), "a");
+        assert_eq!(Value::Char('"').to_json(&interner), "\"\\\"\"");
+        assert_eq!(a.type_name(), "char");
+    }
+
+    #[test]
+    fn formats_numbers_like_reference_lox() {
+        let interner = StringInterner::default();
+        let fmt = |val: f64| Value::Number(val).to_string(&interner);
+
+        assert_eq!(fmt(5.0), "5");
+        assert_eq!(fmt(5.5), "5.5");
+        assert_eq!(fmt(0.0), "0");
+        assert_eq!(fmt(-0.0), "-0");
+        assert_eq!(fmt(-12.0), "-12");
+        assert_eq!(fmt(1e20), "100000000000000000000");
+        assert_eq!(fmt(1e21), "1e21");
+        assert_eq!(fmt(-2.5e300), "-2.5e300");
+        assert_eq!(fmt(1.5e-7), "1.5e-7");
+        assert_eq!(fmt(f64::INFINITY), "inf");
+        assert_eq!(Value::Number(1e21).to_json(&interner), "1e21");
+    }
+
+    #[test]
+    fn compares_strings_across_variants() {
+        let mut interner = StringInterner::default();
+        let long = "a".repeat(4096);
+        let low = Value::InternedString(interner.get_or_intern(&long));
+        let high = Value::InternedString(interner.get_or_intern(long.clone() + "b"));
+        let owned = Value::String(long + "c");
+
+        assert!(low.less(&high, &interner).unwrap());
+        assert!(high.greater(&low, &interner).unwrap());
+        assert!(!low.greater(&low, &interner).unwrap());
+        assert!(high.less(&owned, &interner).unwrap());
+        assert!(owned.greater(&low, &interner).unwrap());
+        assert!(Value::Char('a').less(&Value::Char('b'), &interner).unwrap());
+        assert!(low.less(&Value::Char('b'), &interner).is_err());
+    }
+
+    #[test]
+    fn identical_references_are_equal() {
+        let mut interner = StringInterner::default();
+        let symbol = interner.get_or_intern("a".repeat(4096));
+        let function = Rc::new(Function::script(Chunk::default()));
+
+        assert!(Value::InternedString(symbol).equal(&Value::InternedString(symbol)));
+        assert!(Value::Function(Rc::clone(&function)).equal(&Value::Function(function)));
+
+        let a = Value::Function(Rc::new(Function::script(Chunk::default())));
+        let b = Value::Function(Rc::new(Function::script(Chunk::default())));
+        assert!(!a.equal(&b));
     }
 }
@@ -0,0 +1,128 @@
+use std::fmt::Display;
+
+use string_interner::StringInterner;
+
+use super::{bytecode::Chunk, value::Value};
+
+/// Errors carry the path of the chunk they were found in, such as `script`
+/// for the top level or `script/f/g` for a function `g` declared inside `f`,
+/// followed by the index of the offending constant in that chunk's pool.
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// An interned string constant isn't in the interner, e.g. because the
+    /// chunk was compiled against a different one.
+    UnresolvedSymbol(String, usize),
+    /// The name of a function constant isn't in the interner.
+    UnresolvedFunctionName(String, usize),
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::UnresolvedSymbol(path, idx) => {
+                write!(
+                    f,
+                    "String constant {} in {} is not in the interner",
+                    idx, path
+                )
+            }
+            VerifyError::UnresolvedFunctionName(path, idx) => write!(
+                f,
+                "Name of function constant {} in {} is not in the interner",
+                idx, path
+            ),
+        }
+    }
+}
+
+/// Checks that `chunk`, including the bodies of the functions it defines, can
+/// be run against `interner`.
+pub fn verify_chunk(chunk: &Chunk, interner: &StringInterner) -> Result<(), VerifyError> {
+    verify_chunk_at(chunk, "script", interner)
+}
+
+fn verify_chunk_at(
+    chunk: &Chunk,
+    path: &str,
+    interner: &StringInterner,
+) -> Result<(), VerifyError> {
+    for (idx, constant) in chunk.constants().iter().enumerate() {
+        match constant {
+            Value::InternedString(symbol) if interner.resolve(*symbol).is_none() => {
+                return Err(VerifyError::UnresolvedSymbol(path.to_string(), idx))
+            }
+            Value::Function(function) => {
+                let name = function.name().and_then(|name| interner.resolve(name));
+                let name =
+                    name.ok_or_else(|| VerifyError::UnresolvedFunctionName(path.to_string(), idx))?;
+                let path = format!("{}/{}", path, name);
+                verify_chunk_at(function.chunk(), &path, interner)?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::vm::{compiler::compile, value::Function};
+
+    #[test]
+    fn accepts_chunks_compiled_against_the_interner() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("fun f() { print \"a\"; } print \"b\";", &mut interner).unwrap();
+
+        assert_eq!(verify_chunk(&chunk, &interner), Ok(()));
+    }
+
+    #[test]
+    fn rejects_symbols_missing_from_the_interner() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("print 1; print \"a\" + \"b\";", &mut interner).unwrap();
+
+        let mut other = StringInterner::default();
+        assert_eq!(
+            verify_chunk(&chunk, &other),
+            Err(VerifyError::UnresolvedSymbol("script".to_string(), 1))
+        );
+
+        // Function bodies are checked too: only the name `f` resolves here.
+        let mut interner = StringInterner::default();
+        let chunk = compile("fun f() { print \"x\"; }", &mut interner).unwrap();
+        other.get_or_intern("f");
+        let err = verify_chunk(&chunk, &other).unwrap_err();
+        assert_eq!(
+            err,
+            VerifyError::UnresolvedSymbol("script/f".to_string(), 0)
+        );
+        assert_eq!(
+            err.to_string(),
+            "String constant 0 in script/f is not in the interner"
+        );
+    }
+
+    #[test]
+    fn rejects_function_names_missing_from_the_interner() {
+        let mut interner = StringInterner::default();
+        interner.get_or_intern("a");
+        let name = interner.get_or_intern("b");
+        let mut chunk = Chunk::default();
+        let function = Function {
+            arity: 0,
+            chunk: Chunk::default(),
+            name: Some(name),
+        };
+        chunk.add_value(Value::Function(Rc::new(function)));
+
+        let mut other = StringInterner::default();
+        other.get_or_intern("a");
+        assert_eq!(
+            verify_chunk(&chunk, &other),
+            Err(VerifyError::UnresolvedFunctionName("script".to_string(), 0))
+        );
+    }
+}
@@ -1,200 +1,849 @@
-use std::{collections::HashMap, convert::TryFrom};
-use string_interner::{StringInterner, Symbol};
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt::Display,
+    io::{self, Write},
+    rc::Rc,
+};
+use string_interner::{DefaultSymbol, StringInterner};
 
 use super::{
     bytecode::{ByteCode, Chunk, OpCode},
     disassembler::disassemble_instruction,
-    value::Value,
+    value::{Function, NativeFn, Value},
+    verifier::VerifyError,
 };
 
-// FIXME: improve these messages to support line numbers.
 #[derive(Debug)]
 pub enum VmError {
+    /// An error raised by the instruction on the given source line.
+    AtLine(usize, Box<VmError>),
     EmptyStack,
     TypeError(String),
     InvalidVariable(Value), // bad interning
-    UndefinedVariable,
+    UndefinedVariable(String),
+    UndefinedProperty(String),
+    AssignToBuiltin(String),
+    WrongArity(usize, usize), // expected, got
+    DivisionByZero,
+    StackOverflow,
     RuntimeError,
+    IoError(io::Error),
+    /// The chunk failed verification and wasn't run.
+    InvalidChunk(VerifyError),
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::AtLine(line, err) => write!(f, "[line {}] {}", line, err),
+            VmError::EmptyStack => write!(f, "Stack underflow"),
+            VmError::TypeError(msg) => write!(f, "{}", msg),
+            VmError::InvalidVariable(value) => write!(f, "Invalid variable name {:?}", value),
+            VmError::UndefinedVariable(name) => write!(f, "Undefined variable '{}'", name),
+            VmError::UndefinedProperty(name) => write!(f, "Undefined property '{}'", name),
+            VmError::AssignToBuiltin(name) => write!(f, "Cannot assign to built-in '{}'", name),
+            VmError::WrongArity(expected, got) => {
+                write!(f, "Expected {} arguments but got {}", expected, got)
+            }
+            VmError::DivisionByZero => write!(f, "Division by zero"),
+            VmError::StackOverflow => write!(f, "Stack overflow"),
+            VmError::RuntimeError => write!(f, "Runtime error"),
+            VmError::IoError(err) => write!(f, "{}", err),
+            VmError::InvalidChunk(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl VmError {
+    /// Returns the error without its line, if it has one.
+    pub fn kind(&self) -> &VmError {
+        match self {
+            VmError::AtLine(_, err) => err.kind(),
+            err => err,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepResult {
+    Continue,
+    Halt,
+}
+
+/// Renders a value for `print`, replacing the default `Value::to_string`.
+pub type ValueFormatter = Box<dyn Fn(&Value, &StringInterner) -> String>;
+
+/// Observes runtime errors before `run` returns them.
+pub type ErrorHandler = Box<dyn Fn(&VmError)>;
+
+/// The line terminator written after each `print`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    /// `\n`, the default, so output is the same on every platform.
+    Unix,
+    /// `\r\n`.
+    Windows,
+    /// Whichever of the two the host platform uses.
+    Platform,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Unix => "\n",
+            LineEnding::Windows => "\r\n",
+            LineEnding::Platform if cfg!(windows) => "\r\n",
+            LineEnding::Platform => "\n",
+        }
+    }
+}
+
+/// An active function call: the function, the ip within its chunk, and the
+/// stack slot holding the callee, which is where its locals start.
+struct CallFrame {
+    function: Rc<Function>,
+    ip: usize,
+    base: usize,
 }
 
+/// Counts gathered while profiling is enabled.
 #[derive(Default)]
+struct Profile {
+    /// Calls to each Lox function, by name.
+    calls: HashMap<String, u64>,
+    /// Back-edges taken by each loop, by the name of the function containing
+    /// it and the offset in its chunk where the loop starts.
+    loops: HashMap<String, HashMap<usize, u64>>,
+}
+
+/// Names a function in profiles.
+fn profile_name<'a>(function: &Function, interner: &'a StringInterner) -> &'a str {
+    match function.name.and_then(|name| interner.resolve(name)) {
+        Some("") => "<fn>",
+        Some(name) => name,
+        None => "<script>",
+    }
+}
+
+/// Calls nest without recursing on the Rust stack, so this only bounds
+/// runaway recursion in Lox code.
+const FRAMES_MAX: usize = 1024;
+
 pub struct Vm {
-    chunk: Chunk,
-    globals: HashMap<usize, Value>,
-    ip: usize,
+    /// The innermost call is last; the top-level script is always first.
+    frames: Vec<CallFrame>,
+    globals: HashMap<DefaultSymbol, Value>,
+    /// Natives, looked up after `globals`. A script can shadow them by
+    /// declaring a global of the same name, but not assign to them.
+    builtins: HashMap<DefaultSymbol, Value>,
+    /// The names of the natives in the order they were defined; the index of
+    /// each is the id `OpCode::CallNative` calls it by.
+    natives: Vec<DefaultSymbol>,
     stack: Vec<Value>,
+    output: Box<dyn Write>,
+    line_ending: LineEnding,
+    value_formatter: Option<ValueFormatter>,
+    error_handler: Option<ErrorHandler>,
+    /// When set, consecutive identical `print` lines are collapsed. The last
+    /// line and its repeat count are held back until a different line is
+    /// printed or the program ends.
+    dedup_output: bool,
+    repeated_line: Option<(String, usize)>,
+    /// Source lines of the instructions executed so far, when enabled.
+    coverage: Option<BTreeSet<usize>>,
+    profile: Option<Profile>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm {
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            builtins: HashMap::new(),
+            natives: Vec::new(),
+            stack: Vec::new(),
+            output: Box::new(io::stdout()),
+            line_ending: LineEnding::Unix,
+            value_formatter: None,
+            error_handler: None,
+            dedup_output: false,
+            repeated_line: None,
+            coverage: None,
+            profile: None,
+        }
+    }
 }
 
 static TRACE_VM: bool = false;
 
 impl Vm {
     pub fn run(&mut self, chunk: Chunk, interner: &mut StringInterner) -> Result<(), VmError> {
-        // Keep globals but reset the ip and stack.
-        self.chunk = chunk;
-        self.ip = 0;
+        self.run_script(Rc::new(Function::script(chunk)), interner)
+    }
+
+    /// Runs a top-level script that may be shared, e.g. by a program that is
+    /// run repeatedly, without copying its chunk.
+    pub fn run_script(
+        &mut self,
+        script: Rc<Function>,
+        interner: &mut StringInterner,
+    ) -> Result<(), VmError> {
+        self.load_script(script);
+        self.execute(0, interner)
+    }
+
+    /// Calls the global function `name` without arguments and runs it to
+    /// completion, e.g. to start a program's `main` once its top-level
+    /// declarations have run.
+    pub fn call_global(
+        &mut self,
+        name: &str,
+        interner: &mut StringInterner,
+    ) -> Result<(), VmError> {
+        let symbol = interner.get_or_intern(name);
+        let callee = match self
+            .globals
+            .get(&symbol)
+            .or_else(|| self.builtins.get(&symbol))
+        {
+            Some(callee) => callee.clone(),
+            None => return Err(self.abort(VmError::UndefinedVariable(name.to_string()))),
+        };
+        let base = self.stack.len();
+        let depth = self.frames.len();
+        self.stack.push(callee);
+        if let Err(err) = self.call(base, 0, interner) {
+            return Err(self.abort(err));
+        }
+        self.execute(depth, interner)?;
+        // Without a script frame below it, the callee's `Return` halts like
+        // the script's would and leaves its frame and result behind.
+        self.frames.truncate(depth);
+        self.stack.truncate(base);
+        // Only a return from the script flushes held-back output itself.
+        self.flush_repeated_line().map_err(|err| self.abort(err))
+    }
+
+    /// Steps until the program halts or returns from the frames above
+    /// `depth`.
+    fn execute(&mut self, depth: usize, interner: &mut StringInterner) -> Result<(), VmError> {
+        while self.frames.len() > depth {
+            match self.step(interner) {
+                Ok(StepResult::Continue) => {}
+                Ok(StepResult::Halt) => return Ok(()),
+                Err(err) => return Err(self.abort(err)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Cleans up after a runtime error and hands the error back for
+    /// returning.
+    ///
+    /// The whole stack is reset, along with all call frames. That is exactly
+    /// what the failed statement pushed: a top-level statement always starts
+    /// on an empty stack, because top-level variables are globals and a
+    /// block's locals are popped when it ends.
+    fn abort(&mut self, err: VmError) -> VmError {
+        // The chunk can't be resumed after an error. Globals set by earlier
+        // statements are kept.
+        self.frames.clear();
         self.stack.clear();
+        // Don't lose output printed before the error.
+        let _ = self.flush_repeated_line();
+        if let Some(handler) = &self.error_handler {
+            handler(&err);
+        }
+        err
+    }
+
+    /// Redirects `print` output, which goes to stdout by default.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Chooses the line terminator `print` writes, `\n` by default.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.line_ending = line_ending;
+    }
+
+    /// Installs a hook used by `print` to render values.
+    pub fn set_value_formatter(&mut self, formatter: ValueFormatter) {
+        self.value_formatter = Some(formatter);
+    }
+
+    /// Installs a hook called with any runtime error just before `run`
+    /// returns it, e.g. so an embedding host can log it.
+    pub fn set_error_handler(&mut self, handler: ErrorHandler) {
+        self.error_handler = Some(handler);
+    }
 
-        loop {
-            let instr = self.read_byte().ok_or(VmError::RuntimeError)?;
+    /// Collapses runs of identical `print` lines into one line with a
+    /// `(repeated N times)` suffix, like `uniq -c`.
+    pub fn set_dedup_output(&mut self, dedup_output: bool) {
+        self.dedup_output = dedup_output;
+    }
+
+    /// Makes a host function callable from Lox as the built-in `name`.
+    pub fn define_native(&mut self, name: &str, function: NativeFn, interner: &mut StringInterner) {
+        let symbol = interner.get_or_intern(name);
+        self.builtins.insert(symbol, Value::NativeFn(function));
+        if !self.natives.contains(&symbol) {
+            self.natives.push(symbol);
+        }
+    }
+
+    /// Returns the natives `OpCode::CallNative` can call, indexed by id, for
+    /// compiling code to run on this VM. Natives shadowed by a global are
+    /// `None`, so calls to them compile to calls to the global.
+    pub fn native_registry(&self) -> Vec<Option<DefaultSymbol>> {
+        self.natives
+            .iter()
+            .map(|symbol| Some(*symbol).filter(|symbol| !self.globals.contains_key(symbol)))
+            .collect()
+    }
 
-            if TRACE_VM {
-                let mut output = String::new();
-                self.dump_stack(&mut output);
-                disassemble_instruction(&self.chunk, instr, self.ip - 1, &mut output);
-                println!("{}", output.as_str());
+    /// Starts or stops recording which source lines execute. Enabling it
+    /// again clears the lines recorded so far.
+    pub fn enable_coverage(&mut self, enabled: bool) {
+        self.coverage = if enabled { Some(BTreeSet::new()) } else { None };
+    }
+
+    /// Returns the source lines executed since coverage was enabled.
+    pub fn covered_lines(&self) -> BTreeSet<usize> {
+        self.coverage.clone().unwrap_or_default()
+    }
+
+    /// Starts or stops counting how often each function is called and each
+    /// loop repeats. Enabling it again clears the counts so far.
+    pub fn enable_profiling(&mut self, enabled: bool) {
+        self.profile = enabled.then(Profile::default);
+    }
+
+    /// Returns how many times each Lox function was called since profiling
+    /// was enabled, most called first. Natives aren't counted.
+    pub fn call_profile(&self) -> Vec<(String, u64)> {
+        let mut calls: Vec<(String, u64)> = self
+            .profile
+            .iter()
+            .flat_map(|profile| profile.calls.iter())
+            .map(|(name, count)| (name.clone(), *count))
+            .collect();
+        calls.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+        calls
+    }
+
+    /// Returns how many times each loop jumped back to its start since
+    /// profiling was enabled, hottest first. Loops are identified by the
+    /// function containing them and the offset of their start in its chunk.
+    pub fn loop_profile(&self) -> Vec<(String, usize, u64)> {
+        let mut loops: Vec<(String, usize, u64)> = self
+            .profile
+            .iter()
+            .flat_map(|profile| profile.loops.iter())
+            .flat_map(|(name, loops)| {
+                loops
+                    .iter()
+                    .map(move |(start, count)| (name.clone(), *start, *count))
+            })
+            .collect();
+        loops.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, a.1).cmp(&(&b.0, b.1))));
+        loops
+    }
+
+    /// Loads `chunk` for execution, keeping globals but resetting the ip and
+    /// stack.
+    pub fn load_chunk(&mut self, chunk: Chunk) {
+        self.load_script(Rc::new(Function::script(chunk)));
+    }
+
+    fn load_script(&mut self, script: Rc<Function>) {
+        self.frames = vec![CallFrame {
+            function: script,
+            ip: 0,
+            base: 0,
+        }];
+        self.stack.clear();
+    }
+
+    /// Executes a single instruction of the loaded chunk. Errors carry the
+    /// instruction's source line.
+    pub fn step(&mut self, interner: &mut StringInterner) -> Result<StepResult, VmError> {
+        let depth = self.frames.len();
+        let ip = self.frames.last().map_or(0, |frame| frame.ip);
+        if self.coverage.is_some() {
+            let line = self.line_in_frame(depth, ip);
+            if let (Some(lines), Some(line)) = (&mut self.coverage, line) {
+                lines.insert(line);
             }
+        }
+        // Looking up the line is only worth it once something has failed.
+        self.execute_instruction(interner)
+            .map_err(|err| match self.line_in_frame(depth, ip) {
+                Some(line) => VmError::AtLine(line, Box::new(err)),
+                None => err,
+            })
+    }
 
-            let opcode = OpCode::try_from(instr).or(Result::Err(VmError::RuntimeError))?;
+    /// Returns the source line of `ip` in the `depth`th call frame.
+    fn line_in_frame(&self, depth: usize, ip: usize) -> Option<usize> {
+        let frame = self.frames.get(depth.checked_sub(1)?)?;
+        Some(frame.function.chunk.get_line(ip))
+    }
 
-            match opcode {
-                OpCode::Constant => {
-                    let constant = self.read_constant().ok_or(VmError::RuntimeError)?.clone();
-                    self.stack.push(constant);
-                }
-                OpCode::Nil => self.stack.push(Value::Nil),
-                OpCode::True => self.stack.push(Value::Bool(true)),
-                OpCode::False => self.stack.push(Value::Bool(false)),
-                OpCode::Pop => {
-                    self.stack.pop().ok_or(VmError::EmptyStack)?;
-                }
-                OpCode::GetGlobal => {
-                    let constant_idx = self.read_byte().ok_or(VmError::RuntimeError)?;
-                    let name = self
-                        .chunk
-                        .get_constant(constant_idx)
-                        .ok_or(VmError::RuntimeError)?;
-                    let value = Vm::load(&mut self.globals, name)?;
-                    self.stack.push(value);
-                }
-                OpCode::DefineGlobal => {
-                    let constant_idx = self.read_byte().ok_or(VmError::RuntimeError)?;
-                    let name = self
-                        .chunk
-                        .get_constant(constant_idx)
-                        .ok_or(VmError::RuntimeError)?;
-                    let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    Vm::store(&mut self.globals, name, value)?;
-                }
-                OpCode::SetGlobal => {
-                    let constant_idx = self.read_byte().ok_or(VmError::RuntimeError)?;
-                    let name = self
-                        .chunk
-                        .get_constant(constant_idx)
-                        .ok_or(VmError::RuntimeError)?;
-                    let value = self.stack.last().ok_or(VmError::EmptyStack)?;
-                    Vm::modify(&mut self.globals, name, value.clone())?;
-                }
-                OpCode::Equal => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    self.stack.push(Value::Bool(a.equal(&b)));
-                }
-                OpCode::Greater => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let result = a.greater(&b)?;
-                    self.stack.push(Value::Bool(result));
-                }
-                OpCode::Less => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let result = a.less(&b)?;
-                    self.stack.push(Value::Bool(result));
-                }
-                OpCode::Add => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let result = a.add(&b, interner)?;
-                    self.stack.push(result);
+    fn execute_instruction(
+        &mut self,
+        interner: &mut StringInterner,
+    ) -> Result<StepResult, VmError> {
+        let instr = self.read_byte().ok_or(VmError::RuntimeError)?;
+
+        if TRACE_VM {
+            let mut output = String::new();
+            self.dump_stack(&mut output);
+            if let Some(frame) = self.frames.last() {
+                let chunk = &frame.function.chunk;
+                disassemble_instruction(chunk, instr, frame.ip - 1, interner, &mut output);
+            }
+            println!("{}", output.as_str());
+        }
+
+        let opcode = OpCode::try_from(instr).or(Result::Err(VmError::RuntimeError))?;
+
+        match opcode {
+            OpCode::Constant => {
+                let constant = self.read_constant().ok_or(VmError::RuntimeError)?;
+                self.stack.push(constant);
+            }
+            OpCode::ConstantLong => {
+                let constant = self.read_constant_long().ok_or(VmError::RuntimeError)?;
+                self.stack.push(constant);
+            }
+            OpCode::Nil => self.stack.push(Value::NIL),
+            OpCode::True => self.stack.push(Value::TRUE),
+            OpCode::False => self.stack.push(Value::FALSE),
+            OpCode::Pop => {
+                self.stack.pop().ok_or(VmError::EmptyStack)?;
+            }
+            OpCode::GetLocal => {
+                let slot = self.read_local_slot().ok_or(VmError::RuntimeError)?;
+                let value = self.stack.get(slot).ok_or(VmError::RuntimeError)?;
+                self.stack.push(value.clone());
+            }
+            OpCode::SetLocal => {
+                let slot = self.read_local_slot().ok_or(VmError::RuntimeError)?;
+                let value = self.stack.last().ok_or(VmError::EmptyStack)?.clone();
+                let local = self.stack.get_mut(slot).ok_or(VmError::RuntimeError)?;
+                *local = value;
+            }
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                let name = self
+                    .read_constant_for(opcode)
+                    .ok_or(VmError::RuntimeError)?;
+                let value = self.load_global(&name, interner)?;
+                self.stack.push(value);
+            }
+            OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                let name = self
+                    .read_constant_for(opcode)
+                    .ok_or(VmError::RuntimeError)?;
+                let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                Vm::store(&mut self.globals, &name, value)?;
+            }
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                let name = self
+                    .read_constant_for(opcode)
+                    .ok_or(VmError::RuntimeError)?;
+                let value = self.stack.last().ok_or(VmError::EmptyStack)?.clone();
+                self.modify_global(&name, value, interner)?;
+            }
+            OpCode::GetProperty | OpCode::GetPropertyLong => {
+                let name = match self.read_constant_for(opcode) {
+                    Some(Value::InternedString(symbol)) => interner.resolve(symbol),
+                    _ => None,
                 }
-                OpCode::Subtract => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let result = a.subtract(&b)?;
-                    self.stack.push(result);
+                .ok_or(VmError::RuntimeError)?;
+                let receiver = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let value = Vm::get_property(&receiver, name, interner)?;
+                self.stack.push(value);
+            }
+            OpCode::Equal => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                self.stack.push(Value::Bool(a.equal(&b)));
+            }
+            OpCode::Greater => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let result = a.greater(&b, interner)?;
+                self.stack.push(Value::Bool(result));
+            }
+            OpCode::Less => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let result = a.less(&b, interner)?;
+                self.stack.push(Value::Bool(result));
+            }
+            OpCode::Add => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let result = a.add(&b, interner)?;
+                self.stack.push(result);
+            }
+            OpCode::Subtract => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let result = a.subtract(&b)?;
+                self.stack.push(result);
+            }
+            OpCode::Multiply => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let result = a.multiply(&b)?;
+                self.stack.push(result);
+            }
+            OpCode::Divide => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let result = a.divide(&b)?;
+                self.stack.push(result);
+            }
+            OpCode::Not => {
+                let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                self.stack.push(Value::Bool(b.is_falsey()));
+            }
+            OpCode::Negate => {
+                let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let negated = value.negate()?;
+                self.stack.push(negated);
+            }
+            OpCode::Print if self.dedup_output => {
+                let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let line = match &self.value_formatter {
+                    Some(formatter) => formatter(&value, interner),
+                    None => value.to_string(interner),
+                };
+                match &mut self.repeated_line {
+                    Some((last, count)) if *last == line => *count += 1,
+                    _ => {
+                        self.flush_repeated_line()?;
+                        self.repeated_line = Some((line, 1));
+                    }
                 }
-                OpCode::Multiply => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let result = a.multiply(&b)?;
-                    self.stack.push(result);
+            }
+            OpCode::Print => {
+                let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let line_ending = self.line_ending.as_str();
+                match &self.value_formatter {
+                    Some(formatter) => {
+                        let line = formatter(&value, interner);
+                        write!(self.output, "{}{}", line, line_ending)
+                    }
+                    None => value
+                        .write_to(&mut self.output, interner)
+                        .and_then(|_| self.output.write_all(line_ending.as_bytes())),
                 }
-                OpCode::Divide => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let a = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let result = a.divide(&b)?;
-                    self.stack.push(result);
+                .map_err(VmError::IoError)?;
+            }
+            OpCode::Jump => {
+                let offset = self.read_short().ok_or(VmError::RuntimeError)?;
+                self.jump_by(offset as isize)?;
+            }
+            OpCode::JumpIfFalse => {
+                let offset = self.read_short().ok_or(VmError::RuntimeError)?;
+                // The condition stays on the stack; the compiler pops it.
+                if self.stack.last().ok_or(VmError::EmptyStack)?.is_falsey() {
+                    self.jump_by(offset as isize)?;
                 }
-                OpCode::Not => {
-                    let b = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    self.stack.push(Value::Bool(b.is_falsey(&interner)));
+            }
+            OpCode::Loop => {
+                let offset = self.read_short().ok_or(VmError::RuntimeError)?;
+                self.jump_by(-(offset as isize))?;
+                if let (Some(profile), Some(frame)) = (&mut self.profile, self.frames.last()) {
+                    let name = profile_name(&frame.function, interner);
+                    // Only allocate the key the first time a function loops.
+                    let loops = match profile.loops.get_mut(name) {
+                        Some(loops) => loops,
+                        None => profile.loops.entry(name.to_string()).or_default(),
+                    };
+                    *loops.entry(frame.ip).or_insert(0) += 1;
                 }
-                OpCode::Negate => {
-                    let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    let negated = value.negate()?;
-                    self.stack.push(negated);
+            }
+            OpCode::Call => {
+                let arg_count = self.read_byte().ok_or(VmError::RuntimeError)? as usize;
+                let base = self
+                    .stack
+                    .len()
+                    .checked_sub(arg_count + 1)
+                    .ok_or(VmError::EmptyStack)?;
+                self.call(base, arg_count, interner)?;
+            }
+            OpCode::CallNative => {
+                let native = self.read_byte().ok_or(VmError::RuntimeError)? as usize;
+                let arg_count = self.read_byte().ok_or(VmError::RuntimeError)? as usize;
+                let base = self
+                    .stack
+                    .len()
+                    .checked_sub(arg_count)
+                    .ok_or(VmError::EmptyStack)?;
+                let symbol = *self.natives.get(native).ok_or(VmError::RuntimeError)?;
+                match self.globals.get(&symbol) {
+                    // The global was declared after the call was compiled, so
+                    // call it the slow way instead.
+                    Some(callee) => {
+                        let callee = callee.clone();
+                        self.stack.insert(base, callee);
+                        self.call(base, arg_count, interner)?;
+                    }
+                    None => {
+                        let native = match self.builtins.get(&symbol) {
+                            Some(Value::NativeFn(native)) => *native,
+                            _ => return Err(VmError::RuntimeError),
+                        };
+                        let result = native(&self.stack[base..], interner)?;
+                        self.stack.truncate(base);
+                        self.stack.push(result);
+                    }
                 }
-                OpCode::Print => {
-                    let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    println!("{}", value.to_string(&interner));
+            }
+            OpCode::Return => {
+                if self.frames.len() <= 1 {
+                    self.flush_repeated_line()?;
+                    return Ok(StepResult::Halt);
                 }
-                OpCode::Return => {
-                    let value = self.stack.pop().ok_or(VmError::EmptyStack)?;
-                    println!("{:?}", value);
-                    return Result::Ok(());
+                let result = self.stack.pop().ok_or(VmError::EmptyStack)?;
+                let frame = self.frames.pop().ok_or(VmError::RuntimeError)?;
+                self.stack.truncate(frame.base);
+                self.stack.push(result);
+            }
+        }
+        Ok(StepResult::Continue)
+    }
+
+    /// Calls the value in stack slot `base` with the `arg_count` arguments
+    /// above it.
+    fn call(
+        &mut self,
+        base: usize,
+        arg_count: usize,
+        interner: &mut StringInterner,
+    ) -> Result<(), VmError> {
+        let function = match &self.stack[base] {
+            Value::Function(function) => Rc::clone(function),
+            Value::NativeFn(native) => {
+                // Natives run to completion without a call frame.
+                let result = native(&self.stack[base + 1..], interner)?;
+                self.stack.truncate(base);
+                self.stack.push(result);
+                return Ok(());
+            }
+            callee => {
+                return Err(VmError::TypeError(format!(
+                    "Can only call functions, got {}",
+                    callee.type_name()
+                )))
+            }
+        };
+        if function.arity != arg_count {
+            return Err(VmError::WrongArity(function.arity, arg_count));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(VmError::StackOverflow);
+        }
+        if let Some(profile) = &mut self.profile {
+            let name = profile_name(&function, interner);
+            // Only allocate the key the first time a function is called.
+            match profile.calls.get_mut(name) {
+                Some(count) => *count += 1,
+                None => {
+                    profile.calls.insert(name.to_string(), 1);
                 }
             }
         }
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            base,
+        });
+        Ok(())
+    }
+
+    fn jump_by(&mut self, offset: isize) -> Result<(), VmError> {
+        let frame = self.frames.last_mut().ok_or(VmError::RuntimeError)?;
+        frame.ip = frame
+            .ip
+            .checked_add_signed(offset)
+            .ok_or(VmError::RuntimeError)?;
+        Ok(())
+    }
+
+    fn flush_repeated_line(&mut self) -> Result<(), VmError> {
+        match self.repeated_line.take() {
+            Some((line, 1)) => write!(self.output, "{}{}", line, self.line_ending.as_str()),
+            Some((line, count)) => write!(
+                self.output,
+                "{} (repeated {} times){}",
+                line,
+                count,
+                self.line_ending.as_str()
+            ),
+            None => Ok(()),
+        }
+        .map_err(VmError::IoError)
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Returns the name and printed value of every global, sorted by name so
+    /// that the output is stable regardless of `HashMap` iteration order.
+    pub fn dump_globals(&self, interner: &StringInterner) -> Vec<(String, String)> {
+        let mut globals: Vec<(String, String)> = self
+            .globals
+            .iter()
+            .map(|(key, value)| {
+                let name = interner
+                    .resolve(*key)
+                    .unwrap_or("<invalid interned string>");
+                (name.to_string(), value.to_string(interner))
+            })
+            .collect();
+        globals.sort();
+        globals
+    }
+
+    /// Built-in properties, currently only `length` on strings.
+    fn get_property(
+        receiver: &Value,
+        name: &str,
+        interner: &StringInterner,
+    ) -> Result<Value, VmError> {
+        match (receiver, name) {
+            (Value::InternedString(symbol), "length") => {
+                let str = interner.resolve(*symbol).ok_or(VmError::RuntimeError)?;
+                Ok(Value::Number(str.chars().count() as f64))
+            }
+            (Value::String(str), "length") => Ok(Value::Number(str.chars().count() as f64)),
+            _ => Err(VmError::UndefinedProperty(name.to_string())),
+        }
+    }
+
+    /// Looks up a global, falling back to the built-in it may shadow.
+    fn load_global(&self, name: &Value, interner: &StringInterner) -> Result<Value, VmError> {
+        match Vm::load(&self.globals, name, interner) {
+            Err(VmError::UndefinedVariable(_)) => Vm::load(&self.builtins, name, interner),
+            result => result,
+        }
+    }
+
+    /// Assigns to a declared global. Built-ins are read-only unless shadowed.
+    fn modify_global(
+        &mut self,
+        name: &Value,
+        value: Value,
+        interner: &StringInterner,
+    ) -> Result<(), VmError> {
+        match Vm::modify(&mut self.globals, name, value, interner) {
+            Err(VmError::UndefinedVariable(var))
+                if Vm::load(&self.builtins, name, interner).is_ok() =>
+            {
+                Err(VmError::AssignToBuiltin(var))
+            }
+            result => result,
+        }
     }
 
-    fn load(map: &mut HashMap<usize, Value>, key: &Value) -> Result<Value, VmError> {
+    fn load(
+        map: &HashMap<DefaultSymbol, Value>,
+        key: &Value,
+        interner: &StringInterner,
+    ) -> Result<Value, VmError> {
         match key {
             Value::InternedString(interned_key) => {
-                match map.get(&interned_key.to_usize()) {
+                match map.get(interned_key) {
                     // FIXME: avoid cloning values here.
                     Some(val) => Ok(val.clone()),
-                    // FIXME: include actual string value here.
-                    None => Err(VmError::UndefinedVariable),
+                    None => Err(VmError::UndefinedVariable(key.to_string(interner))),
                 }
             }
             _ => Err(VmError::InvalidVariable(key.clone())),
         }
     }
 
-    fn store(map: &mut HashMap<usize, Value>, key: &Value, value: Value) -> Result<(), VmError> {
+    fn store(
+        map: &mut HashMap<DefaultSymbol, Value>,
+        key: &Value,
+        value: Value,
+    ) -> Result<(), VmError> {
         match key {
             Value::InternedString(interned_key) => {
-                map.insert(interned_key.to_usize(), value);
+                map.insert(*interned_key, value);
                 Ok(())
             }
             _ => Err(VmError::InvalidVariable(key.clone())),
         }
     }
 
-    fn modify(map: &mut HashMap<usize, Value>, key: &Value, value: Value) -> Result<(), VmError> {
+    fn modify(
+        map: &mut HashMap<DefaultSymbol, Value>,
+        key: &Value,
+        value: Value,
+        interner: &StringInterner,
+    ) -> Result<(), VmError> {
         match key {
-            Value::InternedString(interned_key) => {
-                let map_key = interned_key.to_usize();
-                if map.contains_key(&map_key) {
-                    map.insert(map_key, value);
+            Value::InternedString(interned_key) => match map.get_mut(interned_key) {
+                Some(slot) => {
+                    *slot = value;
                     Ok(())
-                } else {
-                    Err(VmError::UndefinedVariable)
                 }
-            }
+                None => Err(VmError::UndefinedVariable(key.to_string(interner))),
+            },
             _ => Err(VmError::InvalidVariable(key.clone())),
         }
     }
 
     fn read_byte(&mut self) -> Option<ByteCode> {
-        let index = self.ip;
-        self.ip += 1;
-        return self.chunk.get_bytecode(index).copied();
+        let frame = self.frames.last_mut()?;
+        let index = frame.ip;
+        frame.ip += 1;
+        frame.function.chunk.get_bytecode(index).copied()
     }
 
-    fn read_constant(&mut self) -> Option<&Value> {
-        let constant_idx = self.read_byte()?;
-        return self.chunk.get_constant(constant_idx);
+    /// Reads a local's slot operand, returning its absolute stack index.
+    fn read_local_slot(&mut self) -> Option<usize> {
+        let slot = self.read_byte()? as usize;
+        Some(self.frames.last()?.base + slot)
+    }
+
+    /// Reads a big-endian 16-bit operand.
+    fn read_short(&mut self) -> Option<usize> {
+        let high = self.read_byte()? as usize;
+        let low = self.read_byte()? as usize;
+        Some(high << 8 | low)
+    }
+
+    fn read_constant(&mut self) -> Option<Value> {
+        let constant_idx = self.read_byte()? as usize;
+        let frame = self.frames.last()?;
+        frame.function.chunk.get_constant(constant_idx).cloned()
+    }
+
+    /// Reads the three-byte operand of `OpCode::ConstantLong`.
+    fn read_constant_long(&mut self) -> Option<Value> {
+        let high = self.read_byte()? as usize;
+        let constant_idx = high << 16 | self.read_short()?;
+        let frame = self.frames.last()?;
+        frame.function.chunk.get_constant(constant_idx).cloned()
+    }
+
+    /// Reads the constant operand of `opcode`, in either its short or long form.
+    fn read_constant_for(&mut self, opcode: OpCode) -> Option<Value> {
+        if opcode.is_long() {
+            self.read_constant_long()
+        } else {
+            self.read_constant()
+        }
     }
 
     fn dump_stack(&self, output: &mut String) {
@@ -202,6 +851,920 @@ impl Vm {
         for value in &self.stack {
             output.push_str(format!("[{:?}]", value).as_str());
         }
-        output.push_str("\n");
+        output.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::vm::{
+        compiler::{compile, compile_into, compile_into_with_natives, compile_program},
+        natives,
+    };
+
+    /// An output sink that can be inspected after being handed to the `Vm`.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs `source` on `vm`, returning everything it printed.
+    fn run_captured(vm: &mut Vm, source: &str) -> String {
+        let buffer = SharedBuffer::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+        run_source(source, vm, &mut interner);
+        buffer.contents()
+    }
+
+    fn run_source(source: &str, vm: &mut Vm, interner: &mut StringInterner) {
+        let chunk = compile(source, interner).expect("compile failed");
+        vm.run(chunk, interner).expect("run failed");
+    }
+
+    #[test]
+    fn dumps_globals_sorted_by_name() {
+        let source = "var zeta = 3; var alpha = \"a\"; var mid = nil; var beta = true;";
+        let expected = vec![
+            ("alpha".to_string(), "a".to_string()),
+            ("beta".to_string(), "true".to_string()),
+            ("mid".to_string(), "nil".to_string()),
+            ("zeta".to_string(), "3".to_string()),
+        ];
+
+        for _ in 0..3 {
+            let mut vm = Vm::default();
+            let mut interner = StringInterner::default();
+            run_source(source, &mut vm, &mut interner);
+            assert_eq!(vm.dump_globals(&interner), expected);
+        }
+    }
+
+    #[test]
+    fn steps_one_instruction_at_a_time() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        // The negation keeps `1 + 2` from being folded at compile time.
+        let chunk = compile("print -1 + 2;", &mut interner).unwrap();
+        vm.load_chunk(chunk);
+
+        let expected_stacks: [&[f64]; 5] = [&[1.0], &[-1.0], &[-1.0, 2.0], &[1.0], &[]];
+        for expected in expected_stacks.iter() {
+            assert_eq!(vm.step(&mut interner).unwrap(), StepResult::Continue);
+            let stack: Vec<f64> = vm
+                .stack()
+                .iter()
+                .map(|value| match value {
+                    Value::Number(num) => *num,
+                    other => panic!("Unexpected value: {:?}", other),
+                })
+                .collect();
+            assert_eq!(&stack[..], *expected);
+        }
+        assert_eq!(vm.step(&mut interner).unwrap(), StepResult::Halt);
+    }
+
+    #[test]
+    fn print_uses_value_formatter() {
+        let mut vm = Vm::default();
+        vm.set_value_formatter(Box::new(|value, interner| match value {
+            Value::InternedString(_) => value.to_string(interner).to_uppercase(),
+            _ => value.to_string(interner),
+        }));
+
+        let output = run_captured(&mut vm, "print \"hi\"; print 1;");
+        assert_eq!(output, "HI\n1\n");
+    }
+
+    #[test]
+    fn defines_multiple_globals_in_one_statement() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        run_source("var a = 1, b = 2, c;", &mut vm, &mut interner);
+
+        let expected = vec![
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "2".to_string()),
+            ("c".to_string(), "nil".to_string()),
+        ];
+        assert_eq!(vm.dump_globals(&interner), expected);
+    }
+
+    #[test]
+    fn later_declarations_see_earlier_ones() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "var a = 1, b = a + 1; print b;");
+        assert_eq!(output, "2\n");
+    }
+
+    #[test]
+    fn strings_have_a_length_property() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "print \"hello\".length; print \"\".length;");
+        assert_eq!(output, "5\n0\n");
+    }
+
+    #[test]
+    fn unknown_string_property_errors() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        let chunk = compile("\"x\".bogus;", &mut interner).unwrap();
+        match vm.run(chunk, &mut interner).as_ref().map_err(VmError::kind) {
+            Err(VmError::UndefinedProperty(name)) => assert_eq!(name, "bogus"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn runs_snippets_compiled_into_one_chunk() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+
+        let mut chunk = Chunk::default();
+        compile_into("var a = 1;", &mut chunk, &mut interner).unwrap();
+        compile_into("print a + 1;", &mut chunk, &mut interner).unwrap();
+        vm.run(chunk, &mut interner).unwrap();
+
+        assert_eq!(buffer.contents(), "2\n");
+    }
+
+    #[test]
+    fn empty_string_is_truthy() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "print !\"\"; print !!\"\";");
+        assert_eq!(output, "false\ntrue\n");
+    }
+
+    #[test]
+    fn prints_literal_singletons() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "print nil; print true; print false;");
+        assert_eq!(output, "nil\ntrue\nfalse\n");
+
+        let mut interner = StringInterner::default();
+        let mut chunk = Chunk::default();
+        for opcode in [OpCode::Nil, OpCode::True, OpCode::False, OpCode::Return].iter() {
+            chunk.write(*opcode as ByteCode, 1);
+        }
+        vm.load_chunk(chunk);
+        for _ in 0..3 {
+            assert_eq!(vm.step(&mut interner).unwrap(), StepResult::Continue);
+        }
+        assert!(matches!(
+            vm.stack(),
+            [Value::Nil, Value::Bool(true), Value::Bool(false)]
+        ));
+    }
+
+    #[test]
+    fn defines_reads_and_assigns_globals() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "var a = 1; print a; a = a + 1; print a;");
+        assert_eq!(output, "1\n2\n");
+    }
+
+    #[test]
+    fn assignment_leaves_its_value_on_the_stack() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "var a; var b; a = b = 3; print a; print b;");
+        assert_eq!(output, "3\n3\n");
+    }
+
+    #[test]
+    fn assigning_an_undefined_global_errors() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        let chunk = compile("missing = 1;", &mut interner).unwrap();
+        match vm.run(chunk, &mut interner).as_ref().map_err(VmError::kind) {
+            Err(VmError::UndefinedVariable(name)) => assert_eq!(name, "missing"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        // The failed assignment must not have defined the variable.
+        assert!(vm.dump_globals(&interner).is_empty());
+    }
+
+    #[test]
+    fn block_locals_are_not_visible_after_the_block() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        let chunk = compile(
+            "{ var inner = 1; print inner; } print inner;",
+            &mut interner,
+        )
+        .unwrap();
+        match vm.run(chunk, &mut interner).as_ref().map_err(VmError::kind) {
+            Err(VmError::UndefinedVariable(name)) => assert_eq!(name, "inner"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undefined_global_error_names_the_variable() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        let chunk = compile("var defined = 1; print missing;", &mut interner).unwrap();
+        match vm.run(chunk, &mut interner).as_ref().map_err(VmError::kind) {
+            Err(VmError::UndefinedVariable(name)) => assert_eq!(name, "missing"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compiled_program_runs_repeatably() {
+        let program = compile_program("var s = \"a\" + \"b\"; s = s + s; print s + \"!\";")
+            .expect("compile failed");
+        let strings = program.interner().len();
+
+        let mut outputs = Vec::new();
+        for _ in 0..2 {
+            let buffer = SharedBuffer::default();
+            let mut vm = Vm::default();
+            vm.set_output(Box::new(buffer.clone()));
+            program.run(&mut vm).expect("run failed");
+            outputs.push(buffer.contents());
+            // The program's chunk is shared with the Vm rather than copied.
+            assert!(std::ptr::eq(vm.frames[0].function.chunk(), program.chunk()));
+        }
+        assert_eq!(outputs, ["abab!\n", "abab!\n"]);
+        // Runtime concatenation must not leak into the program's table.
+        assert_eq!(program.interner().len(), strings);
+    }
+
+    #[test]
+    fn dedup_output_collapses_repeated_lines() {
+        let mut vm = Vm::default();
+        vm.set_dedup_output(true);
+        let output = run_captured(
+            &mut vm,
+            "print \"x\"; print \"x\"; print \"x\"; print \"y\"; print \"x\";",
+        );
+        assert_eq!(output, "x (repeated 3 times)\ny\nx\n");
+    }
+
+    #[test]
+    fn locals_shadow_in_nested_scopes() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "var a = \"global\"; { var a = \"outer\"; { var a = \"inner\"; print a; } print a; } print a;",
+        );
+        assert_eq!(output, "inner\nouter\nglobal\n");
+    }
+
+    #[test]
+    fn reads_and_assigns_locals_declared_earlier() {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        let buffer = SharedBuffer::default();
+        vm.set_output(Box::new(buffer.clone()));
+        run_source(
+            "{ var a = 1; var b = a + 1; a = b * 10; print a; print b; }",
+            &mut vm,
+            &mut interner,
+        );
+        assert_eq!(buffer.contents(), "20\n2\n");
+        assert!(vm.dump_globals(&interner).is_empty());
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn error_handler_observes_runtime_errors() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::default();
+        let handler_seen = seen.clone();
+        vm.set_error_handler(Box::new(move |err| {
+            handler_seen.borrow_mut().push(err.to_string())
+        }));
+
+        let mut interner = StringInterner::default();
+        let chunk = compile("print 1 + \"x\";", &mut interner).unwrap();
+        let result = vm.run(chunk, &mut interner);
+        assert!(matches!(
+            result.as_ref().map_err(VmError::kind),
+            Err(VmError::TypeError(_))
+        ));
+        assert_eq!(*seen.borrow(), [result.unwrap_err().to_string()]);
+    }
+
+    #[test]
+    fn while_loop_sums_a_range() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "var sum = 0; var i = 1; while (i <= 5) { sum = sum + i; i = i + 1; } print sum;",
+        );
+        assert_eq!(output, "15\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn if_runs_only_the_taken_branch() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "if (true) print 1; else print 2; if (nil) print 3; else print 4; if (false) print 5;",
+        );
+        assert_eq!(output, "1\n4\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn for_loop_counts() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "for (var i = 0; i < 3; i = i + 1) print i;");
+        assert_eq!(output, "0\n1\n2\n");
+        // The loop variable is scoped to the loop.
+        assert!(vm.dump_globals(&StringInterner::default()).is_empty());
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn for_loop_clauses_are_optional() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "var i = 0; for (; i < 2;) { print i; i = i + 1; } for (i = 5; i < 7; i = i + 1) print i;",
+        );
+        assert_eq!(output, "0\n1\n5\n6\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn records_only_executed_lines() {
+        let mut vm = Vm::default();
+        vm.enable_coverage(true);
+        let source = "var a = 1;\nif (a > 2)\n  print \"big\";\nelse\n  print \"small\";\n";
+        let output = run_captured(&mut vm, source);
+        assert_eq!(output, "small\n");
+
+        let covered: Vec<usize> = vm.covered_lines().into_iter().collect();
+        assert_eq!(covered, [1, 2, 5]);
+    }
+
+    #[test]
+    fn and_or_return_the_deciding_operand() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "print nil and 1; print 2 and 3; print nil or \"x\"; print 4 or 5; print false or nil;",
+        );
+        assert_eq!(output, "nil\n3\nx\n4\nnil\n");
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "var hits = 0; false and (hits = 1); true or (hits = 2); true and (hits = hits + 10); print hits;",
+        );
+        assert_eq!(output, "10\n");
+    }
+
+    #[test]
+    fn does_not_fold_across_a_short_circuit() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "print (\"a\" or \"b\") + \"c\";");
+        assert_eq!(output, "ac\n");
+    }
+
+    fn run_error(source: &str) -> VmError {
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        let chunk = compile(source, &mut interner).expect("compile failed");
+        vm.run(chunk, &mut interner).expect_err("run succeeded")
+    }
+
+    /// Runs `source` on a fresh Vm with the built-in natives, returning the
+    /// result and everything it printed.
+    fn run_with_output(source: &str) -> (Result<(), VmError>, String) {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+        natives::define_builtins(&mut vm, &mut interner);
+        let chunk = compile(source, &mut interner).expect("compile failed");
+        let result = vm.run(chunk, &mut interner);
+        (result, buffer.contents())
+    }
+
+    #[test]
+    fn calls_a_function_without_arguments() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "fun hello() { print \"hi\"; } hello(); print hello(); print hello;",
+        );
+        assert_eq!(output, "hi\nhi\nnil\n<fn hello>\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn calls_a_function_with_arguments() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "fun add(a, b) { var sum = a + b; print sum; } add(1, 2); add(\"a\", \"b\");",
+        );
+        assert_eq!(output, "3\nab\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn locals_are_relative_to_the_call_frame() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "fun inner(z) { var w = z + 1; print w; }
+             fun outer(x) { var y = x * 2; inner(y); print x; print y; }
+             { var unrelated = 0; outer(5); }",
+        );
+        assert_eq!(output, "11\n5\n10\n");
+    }
+
+    #[test]
+    fn call_errors() {
+        match run_error("fun f(a) {} f(1, 2);").kind() {
+            VmError::WrongArity(expected, got) => assert_eq!((*expected, *got), (1, 2)),
+            other => panic!("Unexpected error: {:?}", other),
+        }
+        assert_eq!(
+            run_error("var x = 1; x();").to_string(),
+            "[line 1] Can only call functions, got number"
+        );
+        assert!(matches!(
+            run_error("fun f() { f(); } f();").kind(),
+            VmError::StackOverflow
+        ));
+    }
+
+    #[test]
+    fn returns_early_from_a_function() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "fun sign(n) { if (n < 0) return -1; if (n == 0) { var zero = 0; return zero; } return 1; }
+             print sign(-5); print sign(0); print sign(7);",
+        );
+        assert_eq!(output, "-1\n0\n1\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn returns_nil_implicitly() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "fun implicit() { var a = 1; } fun bare() { return; } print implicit(); print bare();",
+        );
+        assert_eq!(output, "nil\nnil\n");
+    }
+
+    #[test]
+    fn functions_can_recurse() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(15);",
+        );
+        assert_eq!(output, "610\n");
+    }
+
+    #[test]
+    fn runtime_errors_leave_no_partial_state() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+
+        // As in the REPL: one line fails partway through a call, the next
+        // runs on the same Vm.
+        let chunk = compile(
+            "var a = 1; fun f(x) { var y = 2; return x + y + nil; } { var b = 3; print f(a); }",
+            &mut interner,
+        )
+        .unwrap();
+        assert!(vm.run(chunk, &mut interner).is_err());
+        assert!(vm.stack().is_empty());
+
+        run_source("{ var c = 10; print a + c; }", &mut vm, &mut interner);
+        assert_eq!(buffer.contents(), "11\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn calls_registered_natives() {
+        fn add(args: &[Value], _interner: &mut StringInterner) -> Result<Value, VmError> {
+            match args {
+                [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a + b)),
+                _ => Err(VmError::TypeError("add requires two numbers".to_string())),
+            }
+        }
+
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+        vm.define_native("add", add, &mut interner);
+        natives::define_builtins(&mut vm, &mut interner);
+
+        run_source(
+            "print add(1, 2) * 2; print add; var t = clock(); print t > 0;",
+            &mut vm,
+            &mut interner,
+        );
+        assert_eq!(buffer.contents(), "6\n<native fn>\ntrue\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn prints_char_literals() {
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, "print 'a'; print 'a' == 'a'; print '\\t' == 'b';");
+        assert_eq!(output, "a\ntrue\nfalse\n");
+    }
+
+    #[test]
+    fn calls_a_global_function_by_name() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+
+        run_source("fun main() { print \"hi\"; }", &mut vm, &mut interner);
+        assert_eq!(buffer.contents(), "");
+        vm.call_global("main", &mut interner).unwrap();
+        assert_eq!(buffer.contents(), "hi\n");
+        assert!(vm.stack().is_empty());
+
+        // An explicit call works as before.
+        run_source("main();", &mut vm, &mut interner);
+        assert_eq!(buffer.contents(), "hi\nhi\n");
+
+        match vm.call_global("missing", &mut interner) {
+            Err(VmError::UndefinedVariable(name)) => assert_eq!(name, "missing"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn is_int_checks_for_whole_numbers() {
+        let (result, output) = run_with_output(
+            "print is_int(4); print is_int(4.5); print is_int(\"x\"); print is_int(1/0); print is_int(-0);",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "true\nfalse\nfalse\nfalse\ntrue\n");
+
+        let (result, _) = run_with_output("is_int(1, 2);");
+        assert!(matches!(
+            result.as_ref().map_err(VmError::kind),
+            Err(VmError::WrongArity(1, 2))
+        ));
+    }
+
+    #[test]
+    fn runtime_errors_report_their_line() {
+        let err = run_error("var a = 1;\nprint \"a\" - 1;");
+        assert!(matches!(err, VmError::AtLine(2, _)));
+        assert_eq!(
+            err.to_string(),
+            "[line 2] Operands must be numbers, got string and number"
+        );
+
+        // Errors inside a function point into its body.
+        let err = run_error("fun f() {\n  return -nil;\n}\nf();");
+        assert_eq!(
+            err.to_string(),
+            "[line 2] Operand must be a number, got nil"
+        );
+    }
+
+    #[test]
+    fn addresses_more_than_256_constants() {
+        let mut source = "var last;".to_string();
+        for i in 0..300 {
+            source.push_str(&format!("last = {};", i));
+        }
+        source.push_str("print last; print 299 + 1; print \"late\";");
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, &source);
+
+        assert_eq!(output, "299\n300\nlate\n");
+    }
+
+    #[test]
+    fn print_uses_the_configured_line_ending() {
+        let mut vm = Vm::default();
+        assert_eq!(run_captured(&mut vm, "print \"x\";"), "x\n");
+
+        vm.set_line_ending(LineEnding::Windows);
+        assert_eq!(run_captured(&mut vm, "print \"x\";"), "x\r\n");
+
+        vm.set_dedup_output(true);
+        assert_eq!(
+            run_captured(&mut vm, "print 1; print 1; print 2;"),
+            "1 (repeated 2 times)\r\n2\r\n"
+        );
+    }
+
+    #[test]
+    fn natives_are_first_class_values() {
+        let (result, output) = run_with_output(
+            "var f = clock; print f() > 0;
+             fun apply(g, x) { return g(x); } print apply(is_int, 3);
+             fun pick() { return is_int; } print pick()(2.5);
+             { var local = clock; print local == clock; }",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "true\ntrue\nfalse\ntrue\n");
+    }
+
+    #[test]
+    fn addresses_globals_named_after_256_constants() {
+        let mut source = "var last;".to_string();
+        for i in 0..300 {
+            source.push_str(&format!("last = {};", i));
+        }
+        source.push_str("var late = last + 1; late = late * 2; print late;");
+        source.push_str("print \"word\".length;");
+        let mut vm = Vm::default();
+        let output = run_captured(&mut vm, &source);
+
+        assert_eq!(output, "600\n4\n");
+    }
+
+    #[test]
+    fn calls_a_global_function_on_a_fresh_vm() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+        let chunk = compile("fun main() { print \"hi\"; }", &mut interner).unwrap();
+        let main = chunk
+            .constants()
+            .iter()
+            .find(|constant| matches!(constant, Value::Function(_)))
+            .unwrap();
+        vm.globals
+            .insert(interner.get_or_intern("main"), main.clone());
+
+        vm.call_global("main", &mut interner).unwrap();
+        vm.call_global("main", &mut interner).unwrap();
+        assert_eq!(buffer.contents(), "hi\nhi\n");
+        assert!(vm.frames.is_empty());
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn builtins_can_be_shadowed_but_not_assigned() {
+        let (result, _) = run_with_output("clock = 1;");
+        let err = result.unwrap_err();
+        assert!(matches!(err.kind(), VmError::AssignToBuiltin(name) if name == "clock"));
+        assert_eq!(
+            err.to_string(),
+            "[line 1] Cannot assign to built-in 'clock'"
+        );
+
+        let (result, output) = run_with_output(
+            "print clock() > 0; var clock = 1; print clock; clock = 2; print clock;",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "true\n1\n2\n");
+
+        // Built-ins aren't listed among the script's globals.
+        let mut vm = Vm::default();
+        let mut interner = StringInterner::default();
+        natives::define_builtins(&mut vm, &mut interner);
+        assert!(vm.dump_globals(&interner).is_empty());
+    }
+
+    #[test]
+    fn fuses_calls_to_known_natives() {
+        let buffer = SharedBuffer::default();
+        let mut interner = StringInterner::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        natives::define_builtins(&mut vm, &mut interner);
+        let mut run = |source: &str, vm: &mut Vm| {
+            let natives = vm.native_registry();
+            let mut chunk = Chunk::default();
+            compile_into_with_natives(source, &mut chunk, &mut interner, &natives)
+                .expect("compile failed");
+            let fused = chunk
+                .instructions()
+                .any(|instruction| matches!(instruction.opcode, Ok(OpCode::CallNative)));
+            vm.run(chunk, &mut interner).expect("run failed");
+            fused
+        };
+
+        assert!(run("print clock() > 0;", &mut vm));
+        // A local of the same name is called as usual.
+        assert!(!run("{ var clock = str; print clock(1); }", &mut vm));
+        // A global declared by the same code is only known once it runs, so
+        // the fused call falls back to calling it.
+        assert!(run("var clock = str; print clock(2);", &mut vm));
+        // Once declared, it shadows the native when compiling too.
+        assert!(!run("print clock(3);", &mut vm));
+        assert_eq!(buffer.contents(), "true\n1\n2\n3\n");
+    }
+
+    #[test]
+    fn to_number_parses_strings() {
+        let (result, output) = run_with_output(
+            "print to_number(\"3.14\"); print to_number(\"abc\"); print to_number(5);
+             print to_number(\"4\" + \"2\") + 1;",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "3.14\nnil\n5\n43\n");
+
+        let (result, _) = run_with_output("to_number(true);");
+        assert!(matches!(
+            result.as_ref().map_err(VmError::kind),
+            Err(VmError::TypeError(_))
+        ));
+    }
+
+    #[test]
+    fn str_converts_values_to_strings() {
+        let (result, output) = run_with_output(
+            "print str(1) == \"1\"; print str(true) == \"true\"; print str(nil) == \"nil\";
+             print \"x=\" + str(5); fun f() {} print \"f is \" + str(f); print str(str);",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "true\ntrue\ntrue\nx=5\nf is <fn f>\n<native fn>\n");
+    }
+
+    #[test]
+    fn mod_floor_is_never_negative() {
+        let (result, output) = run_with_output(
+            "print mod_floor(-1, 3); print mod_floor(7, 3); print mod_floor(7.5, 2);",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "2\n1\n1.5\n");
+
+        let (result, _) = run_with_output("mod_floor(1, 0);");
+        let err = result.unwrap_err();
+        assert!(matches!(err.kind(), VmError::DivisionByZero));
+        assert_eq!(err.to_string(), "[line 1] Division by zero");
+    }
+
+    #[test]
+    fn evaluates_arguments_left_to_right() {
+        // Without arrays, each argument appends to a global log instead.
+        let (result, output) = run_with_output(
+            "var log = \"\";
+             fun g(x) { print x; log = log + str(x); return x; }
+             fun f(a, b, c) { return a - b - c; }
+             print f(g(1), g(2), g(3)); print log;",
+        );
+        assert!(result.is_ok());
+        assert_eq!(output, "1\n2\n3\n-4\n123\n");
+    }
+
+    #[test]
+    fn profiles_calls_per_function() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+        natives::define_builtins(&mut vm, &mut interner);
+
+        run_source("fun f() {} fun g() { f(); } f();", &mut vm, &mut interner);
+        assert!(vm.call_profile().is_empty());
+
+        vm.enable_profiling(true);
+        run_source(
+            "for (var i = 0; i < 100; i = i + 1) { f(); } g(); clock();",
+            &mut vm,
+            &mut interner,
+        );
+        assert_eq!(
+            vm.call_profile(),
+            [("f".to_string(), 101), ("g".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn profiles_loop_back_edges() {
+        let mut vm = Vm::default();
+        vm.enable_profiling(true);
+        run_captured(
+            &mut vm,
+            "var i = 0; while (i < 1000) i = i + 1;
+             fun f() { var j = 0; while (j < 3) j = j + 1; } f();",
+        );
+        // Each loop starts at its condition, after the variable declaration.
+        assert_eq!(
+            vm.loop_profile(),
+            [("<script>".to_string(), 4, 1000), ("f".to_string(), 2, 3)]
+        );
+    }
+
+    #[test]
+    fn repeats_a_statement_a_number_of_times() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "repeat (3) { print \"x\"; }
+             var n = 0; repeat (2) repeat (n + 1) n = n + 1; print n;
+             repeat (-1) print \"never\"; repeat (0) print \"never\"; print \"done\";",
+        );
+        assert_eq!(output, "x\nx\nx\n3\ndone\n");
+        assert!(vm.stack().is_empty());
+
+        let err = run_error("repeat (\"3\") {}");
+        assert_eq!(
+            err.to_string(),
+            "[line 1] Operand must be a number, got string"
+        );
+    }
+
+    #[test]
+    fn callee_frames_reuse_the_arguments_on_the_stack() {
+        let mut interner = StringInterner::default();
+        let chunk = compile("fun f(a) { return a; }", &mut interner).unwrap();
+        let f = chunk
+            .constants()
+            .iter()
+            .find(|constant| matches!(constant, Value::Function(_)))
+            .unwrap();
+        let arg = Rc::new(Function::script(Chunk::default()));
+
+        let mut vm = Vm::default();
+        vm.stack.push(f.clone());
+        vm.stack.push(Value::Function(Rc::clone(&arg)));
+        vm.call(0, 1, &mut interner).unwrap();
+
+        // The frame's slots start at the callee, so the argument is used
+        // where it is rather than copied into the frame.
+        assert_eq!(vm.frames.last().map(|frame| frame.base), Some(0));
+        assert_eq!(vm.stack.len(), 2);
+        assert_eq!(Rc::strong_count(&arg), 2);
+        assert!(matches!(&vm.stack[1], Value::Function(value) if Rc::ptr_eq(value, &arg)));
+    }
+
+    #[test]
+    fn calls_anonymous_functions() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "print (fun (x) { return x * 2; })(21);
+             var double = fun (x) { return x * 2; };
+             print double(4); print double;
+             fun apply(f) { return f(1); } print apply(fun (x) { return x + 1; });",
+        );
+        assert_eq!(output, "42\n8\n<fn>\n2\n");
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn named_function_expressions_can_recurse() {
+        let mut vm = Vm::default();
+        let output = run_captured(
+            &mut vm,
+            "var fact = fun f(n) { if (n < 2) return 1; return n * f(n - 1); };
+             print fact(5); print fact;",
+        );
+        assert_eq!(output, "120\n<fn f>\n");
+
+        // The name is only in scope inside the function's own body.
+        let err = run_error("var g = fun f() {}; f;");
+        assert!(matches!(err.kind(), VmError::UndefinedVariable(name) if name == "f"));
+    }
+
+    #[test]
+    fn repl_continues_after_an_error_mid_expression() {
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::default();
+        vm.set_output(Box::new(buffer.clone()));
+        let mut interner = StringInterner::default();
+
+        // `10` and `a` are on the stack when the addition fails.
+        let chunk = compile("var a = 1; print 10 + a * (nil + 1);", &mut interner).unwrap();
+        assert!(vm.run(chunk, &mut interner).is_err());
+        assert!(vm.stack().is_empty());
+
+        run_source("{ var b = 2; print a + b; }", &mut vm, &mut interner);
+        assert_eq!(buffer.contents(), "3\n");
+        assert!(vm.stack().is_empty());
     }
 }
@@ -0,0 +1,131 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+/// Writes `source` to a temporary file and runs the interpreter on it.
+fn run_lox(name: &str, args: &[&str], source: &str) -> Output {
+    let path: PathBuf = env::temp_dir().join(format!("rlox-{}-{}.lox", name, std::process::id()));
+    fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn json_mode_prints_json_values() {
+    let output = run_lox("json", &["--json"], "print \"a\"; print 1; print nil;");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\"a\"\n1\nnull\n");
+}
+
+#[test]
+fn dedup_output_collapses_repeated_prints() {
+    let output = run_lox("dedup", &["--dedup-output"], "print 1; print 1; print 2;");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1 (repeated 2 times)\n2\n"
+    );
+}
+
+#[test]
+fn coverage_lists_executed_lines() {
+    let output = run_lox(
+        "coverage",
+        &["--coverage"],
+        "var a = 1;\nif (a > 2)\n  print a;\nprint 2;\n",
+    );
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "2\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Covered lines: 1, 2, 4\n"
+    );
+}
+
+#[test]
+fn successful_run_exits_with_zero() {
+    let output = run_lox("ok", &[], "print 1 + 2;");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+fn compile_error_exits_with_65() {
+    let output = run_lox("compile-error", &[], "print 1 +;");
+    assert_eq!(output.status.code(), Some(65));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn runtime_error_exits_with_70() {
+    let output = run_lox("runtime-error", &[], "print -\"x\";");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[line 1] Operand must be a number, got string\n"
+    );
+}
+
+#[test]
+fn type_error_after_output_exits_with_70() {
+    let output = run_lox("type-error", &[], "print 1;\nprint 1 + \"x\";\nprint 2;");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[line 2] Operands must be two numbers or two strings, got number and string\n"
+    );
+}
+
+#[test]
+fn dump_json_exports_the_chunk() {
+    let output = run_lox("dump-json", &["--dump-json"], "print 1;");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        concat!(
+            r#"{"constants":[1],"code":["#,
+            r#"{"offset":0,"line":1,"opcode":"Constant","operands":[0],"constant":1},"#,
+            r#"{"offset":2,"line":1,"opcode":"Print","operands":[]},"#,
+            r#"{"offset":3,"line":1,"opcode":"Return","operands":[]}"#,
+            "]}\n"
+        )
+    );
+}
+
+#[test]
+fn call_main_runs_main_after_the_script() {
+    let source = "fun main() { print \"hi\"; }\nprint \"top\";";
+    let output = run_lox("call-main", &["--call-main"], source);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "top\nhi\n");
+
+    let output = run_lox("no-call-main", &[], source);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "top\n");
+
+    let output = run_lox("missing-main", &["--call-main"], "print 1;");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "Undefined variable 'main'\n"
+    );
+}
+
+#[test]
+fn warnings_are_printed_to_stderr() {
+    let output = run_lox("warning", &[], "var a;\nif (a = 1) print a;");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "[line 2] Warning: Assignment used as a condition; use '==' to compare or wrap it in \
+         parentheses\n"
+    );
+}